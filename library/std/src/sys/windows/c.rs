@@ -17,6 +17,8 @@ pub type HANDLE = LPVOID;
 pub type HINSTANCE = HANDLE;
 pub type HMODULE = HINSTANCE;
 pub type HRESULT = LONG;
+pub type NTSTATUS = LONG;
+pub type HLOCAL = HANDLE;
 pub type BOOL = c_int;
 pub type BYTE = u8;
 pub type BOOLEAN = BYTE;
@@ -30,6 +32,7 @@ pub type SIZE_T = usize;
 pub type WORD = u16;
 pub type CHAR = c_char;
 pub type ULONG_PTR = usize;
+pub type PAPCFUNC = Option<unsafe extern "system" fn(ULONG_PTR)>;
 pub type ULONG = c_ulong;
 
 pub type LPBOOL = *mut BOOL;
@@ -56,6 +59,7 @@ pub type LPWSAOVERLAPPED_COMPLETION_ROUTINE = *mut c_void;
 
 pub type PCONDITION_VARIABLE = *mut CONDITION_VARIABLE;
 pub type PLARGE_INTEGER = *mut c_longlong;
+pub type PTIMERAPCROUTINE = Option<unsafe extern "system" fn(LPVOID, DWORD, DWORD)>;
 pub type PSRWLOCK = *mut SRWLOCK;
 
 pub type SOCKET = crate::os::windows::raw::SOCKET;
@@ -183,6 +187,7 @@ pub const ERROR_OPERATION_ABORTED: DWORD = 995;
 pub const ERROR_IO_PENDING: DWORD = 997;
 pub const ERROR_SERVICE_REQUEST_TIMEOUT: DWORD = 1053;
 pub const ERROR_COUNTER_TIMEOUT: DWORD = 1121;
+pub const ERROR_NOT_FOUND: DWORD = 1168;
 pub const ERROR_TIMEOUT: DWORD = 1460;
 pub const ERROR_RESOURCE_CALL_TIMED_OUT: DWORD = 5910;
 pub const ERROR_CTX_MODEM_RESPONSE_TIMEOUT: DWORD = 7012;
@@ -196,6 +201,9 @@ pub const ERROR_RUNLEVEL_SWITCH_AGENT_TIMEOUT: DWORD = 15403;
 
 pub const E_NOTIMPL: HRESULT = 0x80004001u32 as HRESULT;
 
+pub const STATUS_SUCCESS: NTSTATUS = 0;
+pub const STATUS_NOT_IMPLEMENTED: NTSTATUS = 0xC0000002u32 as NTSTATUS;
+
 pub const INVALID_HANDLE_VALUE: HANDLE = !0 as HANDLE;
 
 pub const FACILITY_NT_BIT: DWORD = 0x1000_0000;
@@ -268,7 +276,11 @@ pub const FILE_CURRENT: DWORD = 1;
 pub const FILE_END: DWORD = 2;
 
 pub const WAIT_OBJECT_0: DWORD = 0x00000000;
+
+pub const CREATE_WAITABLE_TIMER_HIGH_RESOLUTION: DWORD = 0x00000002;
+pub const TIMER_ALL_ACCESS: DWORD = 0x001F0003;
 pub const WAIT_TIMEOUT: DWORD = 258;
+pub const WAIT_IO_COMPLETION: DWORD = 0x000000C0;
 pub const WAIT_FAILED: DWORD = 0xFFFFFFFF;
 
 pub const PIPE_ACCESS_INBOUND: DWORD = 0x00000001;
@@ -535,6 +547,31 @@ pub struct OVERLAPPED {
     pub hEvent: HANDLE,
 }
 
+#[repr(C)]
+pub struct OSVERSIONINFOW {
+    pub dwOSVersionInfoSize: DWORD,
+    pub dwMajorVersion: DWORD,
+    pub dwMinorVersion: DWORD,
+    pub dwBuildNumber: DWORD,
+    pub dwPlatformId: DWORD,
+    pub szCSDVersion: [WCHAR; 128],
+}
+
+#[repr(C)]
+pub struct SYSTEM_INFO {
+    pub wProcessorArchitecture: WORD,
+    pub wReserved: WORD,
+    pub dwPageSize: DWORD,
+    pub lpMinimumApplicationAddress: LPVOID,
+    pub lpMaximumApplicationAddress: LPVOID,
+    pub dwActiveProcessorMask: ULONG_PTR,
+    pub dwNumberOfProcessors: DWORD,
+    pub dwProcessorType: DWORD,
+    pub dwAllocationGranularity: DWORD,
+    pub wProcessorLevel: WORD,
+    pub wProcessorRevision: WORD,
+}
+
 #[repr(C)]
 #[allow(dead_code)] // we only use some variants
 pub enum ADDRESS_MODE {
@@ -781,6 +818,7 @@ extern "system" {
         lpCompletionRoutine: LPWSAOVERLAPPED_COMPLETION_ROUTINE,
     ) -> c_int;
     pub fn GetCurrentProcessId() -> DWORD;
+    pub fn GetCurrentThreadId() -> DWORD;
     pub fn WSASocketW(
         af: c_int,
         kind: c_int,
@@ -826,6 +864,10 @@ extern "system" {
     pub fn WaitForSingleObject(hHandle: HANDLE, dwMilliseconds: DWORD) -> DWORD;
     pub fn SwitchToThread() -> BOOL;
     pub fn Sleep(dwMilliseconds: DWORD);
+    pub fn SleepEx(dwMilliseconds: DWORD, bAlertable: BOOL) -> DWORD;
+    pub fn GetSystemInfo(lpSystemInfo: *mut SYSTEM_INFO);
+    pub fn GetVersionExW(lpVersionInformation: *mut OSVERSIONINFOW) -> BOOL;
+    pub fn QueueUserAPC(pfnAPC: PAPCFUNC, hThread: HANDLE, dwData: ULONG_PTR) -> DWORD;
     pub fn GetProcessId(handle: HANDLE) -> DWORD;
     pub fn CopyFileExW(
         lpExistingFileName: LPCWSTR,
@@ -932,6 +974,7 @@ extern "system" {
         lpOverlapped: LPOVERLAPPED,
     ) -> BOOL;
     pub fn CloseHandle(hObject: HANDLE) -> BOOL;
+    pub fn LocalFree(hMem: HLOCAL) -> HLOCAL;
     pub fn MoveFileExW(lpExistingFileName: LPCWSTR, lpNewFileName: LPCWSTR, dwFlags: DWORD)
     -> BOOL;
     pub fn SetFilePointerEx(
@@ -992,6 +1035,14 @@ extern "system" {
         bInitialState: BOOL,
         lpName: LPCWSTR,
     ) -> HANDLE;
+    pub fn SetWaitableTimer(
+        hTimer: HANDLE,
+        lpDueTime: *const LARGE_INTEGER,
+        lPeriod: LONG,
+        pfnCompletionRoutine: PTIMERAPCROUTINE,
+        lpArgToCompletionRoutine: LPVOID,
+        fResume: BOOL,
+    ) -> BOOL;
     pub fn WaitForMultipleObjects(
         nCount: DWORD,
         lpHandles: *const HANDLE,
@@ -1053,6 +1104,43 @@ compat_fn! {
                                 lpThreadDescription: LPCWSTR) -> HRESULT {
         SetLastError(ERROR_CALL_NOT_IMPLEMENTED as DWORD); E_NOTIMPL
     }
+    // The returned description must be freed with `LocalFree`.
+    #[allow(dead_code)]
+    pub fn GetThreadDescription(hThread: HANDLE,
+                                ppszThreadDescription: *mut LPWSTR) -> HRESULT {
+        SetLastError(ERROR_CALL_NOT_IMPLEMENTED as DWORD); E_NOTIMPL
+    }
+    // Vista+. Without it no I/O can be canceled, so the fallback reports that
+    // there was nothing to cancel.
+    #[allow(dead_code)]
+    pub fn CancelSynchronousIo(hThread: HANDLE) -> BOOL {
+        SetLastError(ERROR_NOT_FOUND); FALSE
+    }
+    // Vista+. The fallback only fences the calling thread, see
+    // `sys::flush_process_write_buffers`.
+    pub fn FlushProcessWriteBuffers() -> () {
+        crate::sync::atomic::fence(crate::sync::atomic::Ordering::SeqCst)
+    }
+    // Vista+. `CREATE_WAITABLE_TIMER_HIGH_RESOLUTION` additionally needs
+    // Windows 10 1803; older systems reject it with ERROR_INVALID_PARAMETER.
+    pub fn CreateWaitableTimerExW(lpTimerAttributes: LPSECURITY_ATTRIBUTES,
+                                  lpTimerName: LPCWSTR,
+                                  dwFlags: DWORD,
+                                  dwDesiredAccess: DWORD) -> HANDLE {
+        SetLastError(ERROR_CALL_NOT_IMPLEMENTED as DWORD); ptr::null_mut()
+    }
+    // Vista+. The fallback's 0 is never a valid thread id, so callers must
+    // treat it as "unknown".
+    #[allow(dead_code)]
+    pub fn GetThreadId(Thread: HANDLE) -> DWORD {
+        SetLastError(ERROR_CALL_NOT_IMPLEMENTED as DWORD); 0
+    }
+    // Vista+. Without it there's no way to ask, so the fallback spreads
+    // threads out by their id instead (ids are multiples of 4).
+    #[allow(dead_code)]
+    pub fn GetCurrentProcessorNumber() -> DWORD {
+        GetCurrentThreadId() >> 2
+    }
     pub fn SetFileInformationByHandle(_hFile: HANDLE,
                     _FileInformationClass: FILE_INFO_BY_HANDLE_CLASS,
                     _lpFileInformation: LPVOID,
@@ -1096,3 +1184,18 @@ compat_fn! {
         panic!("rwlocks not available")
     }
 }
+
+compat_fn! {
+    ntdll:
+
+    // Unlike `GetVersionExW`, this reports the real version even to
+    // processes without a compatibility manifest. It has been exported since
+    // Windows 2000, so the fallback is only a last resort.
+    pub fn RtlGetVersion(lpVersionInformation: *mut OSVERSIONINFOW) -> NTSTATUS {
+        if GetVersionExW(lpVersionInformation) != 0 {
+            STATUS_SUCCESS
+        } else {
+            STATUS_NOT_IMPLEMENTED
+        }
+    }
+}
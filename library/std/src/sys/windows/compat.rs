@@ -10,17 +10,63 @@
 //! static-per-function which caches the result of the first check. In this
 //! manner we pay a semi-large one-time cost up front for detecting whether a
 //! function is available but afterwards it's just a load and a jump.
+//!
+//! A fallback is free to call other compat functions, for example to build a
+//! missing API out of older ones. Resolution takes no locks and each binding
+//! caches into its own static, so a fallback's first call simply resolves the
+//! functions it uses in turn; the only thing to avoid is a cycle of fallbacks
+//! calling each other.
 
 use crate::ffi::CString;
+#[cfg(test)]
+use crate::ptr;
+#[cfg(test)]
+use crate::sync::atomic::AtomicPtr;
 use crate::sync::atomic::{AtomicUsize, Ordering};
 use crate::sys::c;
 
+// The modules std binds functions from. Their handles are cached after the
+// first lookup since these modules stay loaded for the life of the process.
+const KNOWN_MODULES: [&str; 2] = ["kernel32", "ntdll"];
+static MODULE_HANDLES: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+fn module_handle(module: &str) -> c::HMODULE {
+    let slot = KNOWN_MODULES.iter().position(|&m| m == module).map(|i| &MODULE_HANDLES[i]);
+    if let Some(slot) = slot {
+        // The handle is a plain value with no other data published alongside
+        // it, so a relaxed load is enough.
+        match slot.load(Ordering::Relaxed) {
+            0 => {}
+            n => return n as c::HMODULE,
+        }
+    }
+
+    let mut name: Vec<u16> = module.encode_utf16().collect();
+    name.push(0);
+    let handle = unsafe { c::GetModuleHandleW(name.as_ptr()) };
+    if let Some(slot) = slot {
+        slot.store(handle as usize, Ordering::Relaxed);
+    }
+    handle
+}
+
 pub fn lookup(module: &str, symbol: &str) -> Option<usize> {
-    let mut module: Vec<u16> = module.encode_utf16().collect();
-    module.push(0);
+    #[cfg(test)]
+    {
+        match RESOLVER.load(Ordering::SeqCst) {
+            0 => {}
+            n => unsafe { return crate::mem::transmute::<usize, Resolver>(n)(module, symbol) },
+        }
+    }
+    real_lookup(module, symbol)
+}
+
+/// Resolves `symbol` straight from the OS, bypassing any resolver installed
+/// with `set_resolver`.
+pub fn real_lookup(module: &str, symbol: &str) -> Option<usize> {
     let symbol = CString::new(symbol).unwrap();
     unsafe {
-        let handle = c::GetModuleHandleW(module.as_ptr());
+        let handle = module_handle(module);
         match c::GetProcAddress(handle, symbol.as_ptr()) as usize {
             0 => None,
             n => Some(n),
@@ -29,9 +75,146 @@ pub fn lookup(module: &str, symbol: &str) -> Option<usize> {
 }
 
 pub fn store_func(ptr: &AtomicUsize, module: &str, symbol: &str, fallback: usize) -> usize {
-    let value = lookup(module, symbol).unwrap_or(fallback);
-    ptr.store(value, Ordering::SeqCst);
-    value
+    store_first_func(ptr, &[(module, symbol)], fallback)
+}
+
+/// Like `store_func`, but binds the first of `candidates` that resolves,
+/// trying them in order.
+pub fn store_first_func(ptr: &AtomicUsize, candidates: &[(&str, &str)], fallback: usize) -> usize {
+    let mut value = None;
+    let mut tried = 0;
+    for &(module, symbol) in candidates {
+        tried += 1;
+        #[cfg(test)]
+        {
+            if fallback_forced(symbol) {
+                continue;
+            }
+        }
+        value = lookup(module, symbol);
+        if value.is_some() {
+            break;
+        }
+    }
+    ptr.store(value.unwrap_or(fallback), Ordering::Release);
+
+    // Logged only once the pointer is published, so a logger that itself
+    // ends up calling this binding doesn't recurse into resolving it again.
+    for (i, &(module, symbol)) in candidates[..tried].iter().enumerate() {
+        log_lookup(module, symbol, value.is_some() && i == tried - 1);
+    }
+    value.unwrap_or(fallback)
+}
+
+// Symbols tests have asked to treat as missing, so that fallbacks get
+// exercised on systems where the real function exists. This can't use a
+// `Mutex` since locking one may itself resolve compat functions.
+#[cfg(test)]
+static FORCED_FALLBACKS: [AtomicPtr<&'static str>; 4] = [
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+    AtomicPtr::new(ptr::null_mut()),
+];
+
+/// Makes bindings of `symbol` that haven't been resolved yet use their
+/// fallback, until the returned guard is dropped.
+#[cfg(test)]
+pub fn force_fallback(symbol: &'static str) -> ForcedFallback {
+    let entry = Box::into_raw(Box::new(symbol));
+    for (slot, forced) in FORCED_FALLBACKS.iter().enumerate() {
+        if forced.compare_and_swap(ptr::null_mut(), entry, Ordering::SeqCst).is_null() {
+            return ForcedFallback { slot };
+        }
+    }
+    panic!("too many forced compat fallbacks");
+}
+
+#[cfg(test)]
+pub struct ForcedFallback {
+    slot: usize,
+}
+
+#[cfg(test)]
+impl Drop for ForcedFallback {
+    fn drop(&mut self) {
+        // The entry is leaked rather than freed: a lookup on another thread
+        // may still be comparing against it.
+        FORCED_FALLBACKS[self.slot].store(ptr::null_mut(), Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+pub type Resolver = fn(module: &str, symbol: &str) -> Option<usize>;
+
+// A stand-in for `real_lookup` installed by a test, or 0. Like
+// `FORCED_FALLBACKS` this can't be behind a lock.
+#[cfg(test)]
+static RESOLVER: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes `lookup` call `resolver` instead of asking the OS, until the returned
+/// guard is dropped, so that tests can decide which symbols are missing.
+///
+/// The resolver sees every lookup made in the process meanwhile, including
+/// other tests', so it should pass symbols it doesn't care about on to
+/// `real_lookup`. Bindings that were already resolved aren't affected.
+#[cfg(test)]
+pub fn set_resolver(resolver: Resolver) -> ResolverGuard {
+    ResolverGuard { previous: RESOLVER.swap(resolver as usize, Ordering::SeqCst) }
+}
+
+#[cfg(test)]
+pub struct ResolverGuard {
+    previous: usize,
+}
+
+#[cfg(test)]
+impl Drop for ResolverGuard {
+    fn drop(&mut self) {
+        RESOLVER.store(self.previous, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+fn fallback_forced(symbol: &str) -> bool {
+    FORCED_FALLBACKS.iter().any(|forced| match forced.load(Ordering::SeqCst) {
+        entry if entry.is_null() => false,
+        entry => unsafe { *entry == symbol },
+    })
+}
+
+static LOOKUP_LOGGER: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs `logger` to be called for every symbol a compat binding tries to
+/// resolve, with whether it was found. This makes it possible to tell when a
+/// process ended up on a fallback because an API is missing from the OS.
+///
+/// The previous logger is put back when the returned guard is dropped; forget
+/// the guard to keep `logger` installed for the rest of the process.
+#[allow(dead_code)]
+pub fn set_lookup_logger(
+    logger: fn(module: &str, symbol: &str, resolved: bool),
+) -> LookupLoggerGuard {
+    LookupLoggerGuard { previous: LOOKUP_LOGGER.swap(logger as usize, Ordering::AcqRel) }
+}
+
+pub struct LookupLoggerGuard {
+    previous: usize,
+}
+
+impl Drop for LookupLoggerGuard {
+    fn drop(&mut self) {
+        LOOKUP_LOGGER.store(self.previous, Ordering::Release);
+    }
+}
+
+fn log_lookup(module: &str, symbol: &str, resolved: bool) {
+    match LOOKUP_LOGGER.load(Ordering::Acquire) {
+        0 => {}
+        n => unsafe {
+            crate::mem::transmute::<usize, fn(&str, &str, bool)>(n)(module, symbol, resolved)
+        },
+    }
 }
 
 macro_rules! compat_fn {
@@ -62,7 +245,53 @@ macro_rules! compat_fn {
                 $($body);*
             }
 
-            let addr = match PTR.load(Ordering::SeqCst) {
+            // A relaxed load is sufficient: the only thing published through
+            // `PTR` is the function address itself, which never changes once
+            // resolved. Racing threads either see 0 and resolve it themselves
+            // or see the same address `store_func` would have produced.
+            let addr = match PTR.load(Ordering::Relaxed) {
+                0 => load(),
+                n => n,
+            };
+            mem::transmute::<usize, F>(addr)($($argname),*)
+        }
+    )*)
+}
+
+/// Like `compat_fn!`, but each function lists several `module::symbol`
+/// candidates with the same signature and binds the first one present,
+/// falling back to the body only if none of them resolve.
+#[allow(unused_macros)]
+macro_rules! compat_fn_first {
+    ($(
+        $(#[$meta:meta])*
+        pub fn $name:ident($($argname:ident: $argtype:ty),*)
+                                -> $rettype:ty
+            from [$($module:ident::$symbol:ident),+] {
+            $($body:expr);*
+        }
+    )*) => ($(
+        #[allow(unused_variables)]
+        $(#[$meta])*
+        pub unsafe fn $name($($argname: $argtype),*) -> $rettype {
+            use crate::sync::atomic::{AtomicUsize, Ordering};
+            use crate::mem;
+            type F = unsafe extern "system" fn($($argtype),*) -> $rettype;
+
+            static PTR: AtomicUsize = AtomicUsize::new(0);
+
+            fn load() -> usize {
+                crate::sys::compat::store_first_func(&PTR,
+                                          &[$((stringify!($module), stringify!($symbol))),+],
+                                          fallback as usize)
+            }
+            unsafe extern "system" fn fallback($($argname: $argtype),*)
+                                               -> $rettype {
+                $($body);*
+            }
+
+            // See `compat_fn!` for why a relaxed load is sufficient.
+            let addr = match PTR.load(Ordering::Relaxed) {
                 0 => load(),
                 n => n,
             };
@@ -70,3 +299,6 @@ macro_rules! compat_fn {
         }
     )*)
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,170 @@
+use super::{lookup, module_handle, MODULE_HANDLES};
+use crate::sync::atomic::Ordering;
+use crate::sys::c;
+
+// `SetThreadStackGuarantee` is bound in `c` with a fallback for XP, but every
+// OS we test on (Windows 7 and later) should resolve the real function so that
+// `stack_overflow::Handler` can reserve its guard space.
+#[cfg(not(target_vendor = "uwp"))]
+#[test]
+fn set_thread_stack_guarantee_resolves() {
+    assert!(lookup("kernel32", "SetThreadStackGuarantee").is_some());
+}
+
+#[test]
+fn module_handle_is_cached() {
+    let first = module_handle("kernel32");
+    assert!(!first.is_null());
+    assert_eq!(MODULE_HANDLES[0].load(Ordering::Relaxed), first as usize);
+    assert_eq!(module_handle("kernel32"), first);
+}
+
+// Every thread racing on the first call of a binding must end up calling the
+// same function: either it observes the published address (relaxed is enough
+// since nothing else is published with it) or it resolves it independently
+// and stores the identical value.
+#[test]
+fn racing_first_calls_agree() {
+    use crate::thread;
+
+    compat_fn! {
+        kernel32:
+
+        pub fn GetCurrentThreadId() -> c::DWORD {
+            panic!("GetCurrentThreadId should always resolve")
+        }
+    }
+
+    let threads: Vec<_> = (0..8)
+        .map(|_| thread::spawn(|| unsafe { GetCurrentThreadId() == c::GetCurrentThreadId() }))
+        .collect();
+    for t in threads {
+        assert!(t.join().unwrap());
+    }
+}
+
+#[test]
+fn first_present_candidate_is_bound() {
+    compat_fn_first! {
+        pub fn CurrentThreadId() -> c::DWORD
+            from [kernel32::RustCompatTestMissingFunction, kernel32::GetCurrentThreadId] {
+            0
+        }
+        pub fn NoCandidates() -> c::DWORD
+            from [kernel32::RustCompatTestMissingFunction] {
+            42
+        }
+    }
+
+    unsafe {
+        assert_eq!(CurrentThreadId(), c::GetCurrentThreadId());
+        assert_eq!(NoCandidates(), 42);
+    }
+}
+
+#[test]
+fn lookup_logger_sees_resolutions() {
+    use super::set_lookup_logger;
+    use crate::sync::atomic::AtomicUsize;
+
+    compat_fn! {
+        kernel32:
+
+        pub fn GetCurrentProcessId() -> c::DWORD {
+            0
+        }
+        pub fn RustCompatTestLoggedMissing() -> c::DWORD {
+            0
+        }
+    }
+
+    // Other tests may resolve bindings concurrently, so only look at the two
+    // symbols bound above. 1 means "seen, resolved", 2 "seen, not resolved".
+    static PRESENT: AtomicUsize = AtomicUsize::new(0);
+    static MISSING: AtomicUsize = AtomicUsize::new(0);
+
+    fn logger(_module: &str, symbol: &str, resolved: bool) {
+        let seen = if resolved { 1 } else { 2 };
+        match symbol {
+            "GetCurrentProcessId" => PRESENT.store(seen, Ordering::SeqCst),
+            "RustCompatTestLoggedMissing" => MISSING.store(seen, Ordering::SeqCst),
+            _ => {}
+        }
+    }
+    let _logger = set_lookup_logger(logger);
+
+    unsafe {
+        assert_eq!(GetCurrentProcessId(), c::GetCurrentProcessId());
+        assert_eq!(RustCompatTestLoggedMissing(), 0);
+    }
+    assert_eq!(PRESENT.load(Ordering::SeqCst), 1);
+    assert_eq!(MISSING.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn fallback_can_call_other_bindings() {
+    compat_fn! {
+        kernel32:
+
+        pub fn RustCompatTestMissingThreadId() -> c::DWORD {
+            GetCurrentThreadId()
+        }
+        pub fn GetCurrentThreadId() -> c::DWORD {
+            panic!("GetCurrentThreadId should always resolve")
+        }
+    }
+
+    unsafe {
+        // The first call resolves the missing function to its fallback, which
+        // in turn triggers resolution of `GetCurrentThreadId`.
+        assert_eq!(RustCompatTestMissingThreadId(), c::GetCurrentThreadId());
+        assert_eq!(RustCompatTestMissingThreadId(), c::GetCurrentThreadId());
+    }
+}
+
+#[test]
+fn forced_fallback_is_used() {
+    use super::force_fallback;
+
+    compat_fn! {
+        kernel32:
+
+        pub fn GetVersion() -> c::DWORD {
+            0
+        }
+    }
+
+    let _forced = force_fallback("GetVersion");
+    // The real `GetVersion` never returns 0.
+    assert_eq!(unsafe { GetVersion() }, 0);
+}
+
+#[test]
+fn resolver_can_hide_symbols() {
+    use super::{real_lookup, set_resolver};
+    use crate::sync::atomic::AtomicBool;
+
+    static FALLBACK_RAN: AtomicBool = AtomicBool::new(false);
+
+    compat_fn! {
+        kernel32:
+
+        pub fn GetTickCount() -> c::DWORD {
+            FALLBACK_RAN.store(true, Ordering::SeqCst);
+            0
+        }
+    }
+
+    fn without_tick_count(module: &str, symbol: &str) -> Option<usize> {
+        match (module, symbol) {
+            ("kernel32", "GetTickCount") => None,
+            _ => real_lookup(module, symbol),
+        }
+    }
+
+    let _resolver = set_resolver(without_tick_count);
+    assert_eq!(lookup("kernel32", "GetTickCount"), None);
+    assert!(lookup("kernel32", "GetCurrentThreadId").is_some());
+    unsafe { GetTickCount() };
+    assert!(FALLBACK_RAN.load(Ordering::SeqCst));
+}
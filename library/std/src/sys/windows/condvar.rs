@@ -1,9 +1,13 @@
 use crate::cell::UnsafeCell;
+use crate::cmp;
 use crate::sys::c;
 use crate::sys::mutex::{self, Mutex};
 use crate::sys::os;
 use crate::time::Duration;
 
+#[cfg(test)]
+mod tests;
+
 pub struct Condvar {
     inner: UnsafeCell<c::CONDITION_VARIABLE>,
 }
@@ -26,12 +30,7 @@ impl Condvar {
     }
 
     pub unsafe fn wait_timeout(&self, mutex: &Mutex, dur: Duration) -> bool {
-        let r = c::SleepConditionVariableSRW(
-            self.inner.get(),
-            mutex::raw(mutex),
-            super::dur2timeout(dur),
-            0,
-        );
+        let r = c::SleepConditionVariableSRW(self.inner.get(), mutex::raw(mutex), timeout(dur), 0);
         if r == 0 {
             debug_assert_eq!(os::errno() as usize, c::ERROR_TIMEOUT as usize);
             false
@@ -54,3 +53,11 @@ impl Condvar {
         // ...
     }
 }
+
+// Converts `dur` to the millisecond timeout for `SleepConditionVariableSRW`.
+// Sub-millisecond durations are rounded up by `dur2timeout` so they don't turn
+// into a zero (non-blocking) wait, and durations too long to represent are
+// clamped just below `INFINITE` so a finite timeout never waits forever.
+fn timeout(dur: Duration) -> c::DWORD {
+    cmp::min(super::dur2timeout(dur), c::INFINITE - 1)
+}
@@ -0,0 +1,20 @@
+use super::timeout;
+use crate::sys::c;
+use crate::time::Duration;
+
+#[test]
+fn sub_millisecond_rounds_up() {
+    assert_eq!(timeout(Duration::from_nanos(0)), 0);
+    assert_eq!(timeout(Duration::from_nanos(1)), 1);
+    assert_eq!(timeout(Duration::from_nanos(999_999)), 1);
+    assert_eq!(timeout(Duration::from_nanos(1_000_001)), 2);
+    assert_eq!(timeout(Duration::from_millis(5)), 5);
+}
+
+#[test]
+fn overflow_clamps_below_infinite() {
+    let max_finite = Duration::from_millis(c::INFINITE as u64 - 1);
+    assert_eq!(timeout(max_finite), c::INFINITE - 1);
+    assert_eq!(timeout(max_finite + Duration::from_millis(1)), c::INFINITE - 1);
+    assert_eq!(timeout(Duration::new(u64::MAX, 999_999_999)), c::INFINITE - 1);
+}
@@ -0,0 +1,37 @@
+//! Lock contention reporting
+//!
+//! When the `lock_contention_hook` feature is enabled, `Mutex::lock` and
+//! `RWLock::{read, write}` first attempt a non-blocking acquisition. If that
+//! fails they call the installed hook with the name of the operation before
+//! blocking. Without the feature none of this code is compiled in.
+
+use crate::sync::atomic::{AtomicUsize, Ordering};
+
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs `hook` to be called whenever a lock acquisition has to block.
+///
+/// The hook runs on the blocking thread before it waits, so it must not
+/// itself acquire the lock being waited on.
+#[allow(dead_code)]
+pub fn set_contention_hook(hook: fn(&'static str)) {
+    HOOK.store(hook as usize, Ordering::Release);
+}
+
+/// Acquires a lock by first calling `try_acquire` and, if that fails,
+/// reporting `what` to the hook and then calling `acquire`.
+#[inline]
+pub fn acquire(what: &'static str, try_acquire: impl FnOnce() -> bool, acquire: impl FnOnce()) {
+    if !try_acquire() {
+        report(what);
+        acquire()
+    }
+}
+
+#[cold]
+fn report(what: &'static str) {
+    match HOOK.load(Ordering::Acquire) {
+        0 => {}
+        n => unsafe { crate::mem::transmute::<usize, fn(&'static str)>(n)(what) },
+    }
+}
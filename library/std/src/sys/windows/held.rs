@@ -0,0 +1,27 @@
+//! Per-thread held lock tracking
+//!
+//! When the `lock_held_tracking` feature is enabled in a debug build, the
+//! sys `Mutex` counts how many mutexes the current thread holds, so that
+//! diagnostics can flag a thread that blocks or parks while holding one.
+//! Without the feature, or in release builds, none of this code is compiled
+//! in.
+
+use crate::cell::Cell;
+
+thread_local!(static HELD: Cell<usize> = Cell::new(0));
+
+/// Returns how many sys mutexes the current thread holds.
+#[allow(dead_code)]
+pub fn held_lock_count() -> usize {
+    HELD.try_with(|held| held.get()).unwrap_or(0)
+}
+
+// `try_with` since mutexes can still be used while the thread's TLS is being
+// torn down; those acquisitions just go uncounted.
+pub fn acquired() {
+    let _ = HELD.try_with(|held| held.set(held.get() + 1));
+}
+
+pub fn released() {
+    let _ = HELD.try_with(|held| held.set(held.get().saturating_sub(1)));
+}
@@ -1,10 +1,13 @@
 #![allow(missing_docs, nonstandard_style)]
 
+use crate::cmp;
 use crate::ffi::{OsStr, OsString};
 use crate::io::ErrorKind;
+use crate::mem;
 use crate::os::windows::ffi::{OsStrExt, OsStringExt};
 use crate::path::PathBuf;
 use crate::ptr;
+use crate::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use crate::time::Duration;
 
 pub use self::rand::hashmap_random_keys;
@@ -18,10 +21,14 @@ pub mod args;
 pub mod c;
 pub mod cmath;
 pub mod condvar;
+#[cfg(feature = "lock_contention_hook")]
+pub mod contention;
 pub mod env;
 pub mod ext;
 pub mod fs;
 pub mod handle;
+#[cfg(all(debug_assertions, feature = "lock_held_tracking"))]
+pub mod held;
 pub mod io;
 pub mod memchr;
 pub mod mutex;
@@ -49,6 +56,9 @@ cfg_if::cfg_if! {
     }
 }
 
+#[cfg(test)]
+mod tests;
+
 #[cfg(not(test))]
 pub fn init() {}
 
@@ -300,6 +310,82 @@ pub fn dur2timeout(dur: Duration) -> c::DWORD {
         .unwrap_or(c::INFINITE)
 }
 
+/// The number of logical processors on the system, queried once and cached.
+///
+/// The spinning lock paths use this to skip spinning on uniprocessor systems,
+/// where the holder can't make progress while the waiter spins.
+pub(crate) fn ncpus() -> usize {
+    static NCPUS: AtomicUsize = AtomicUsize::new(0);
+
+    let n = NCPUS.load(Ordering::Relaxed);
+    if n != 0 {
+        return n;
+    }
+    let n = unsafe {
+        let mut info: c::SYSTEM_INFO = mem::zeroed();
+        c::GetSystemInfo(&mut info);
+        cmp::max(info.dwNumberOfProcessors as usize, 1)
+    };
+    NCPUS.store(n, Ordering::Relaxed);
+    n
+}
+
+/// Picks one of `slots` per-CPU slots for the processor the current thread is
+/// running on.
+///
+/// The thread may be moved to another processor at any time, so this is
+/// only a hint. Before Vista the processor is derived from the thread id
+/// instead, which isn't bounded by `ncpus()`, so the result is reduced modulo
+/// `slots` here. `slots` must not be zero.
+#[allow(dead_code)]
+pub(crate) fn current_cpu(slots: usize) -> usize {
+    unsafe { c::GetCurrentProcessorNumber() as usize % slots }
+}
+
+/// The `(major, minor, build)` version of the running Windows, queried once
+/// and cached.
+///
+/// This comes from `RtlGetVersion`, which isn't subject to the version lie
+/// `GetVersionExW` tells processes without a manifest on Windows 8.1 and up.
+#[allow(dead_code)]
+pub(crate) fn os_version() -> (u32, u32, u32) {
+    // Packed as a set top bit, then 15 bits of major version, 16 of minor and
+    // 32 of build number, so 0 can mean "not queried yet".
+    static VERSION: AtomicU64 = AtomicU64::new(0);
+
+    let mut packed = VERSION.load(Ordering::Relaxed);
+    if packed == 0 {
+        let (major, minor, build) = unsafe {
+            let mut info: c::OSVERSIONINFOW = mem::zeroed();
+            info.dwOSVersionInfoSize = mem::size_of::<c::OSVERSIONINFOW>() as c::DWORD;
+            if c::RtlGetVersion(&mut info) == c::STATUS_SUCCESS {
+                (info.dwMajorVersion, info.dwMinorVersion, info.dwBuildNumber)
+            } else {
+                (0, 0, 0)
+            }
+        };
+        packed = 1 << 63
+            | (major as u64 & 0x7fff) << 48
+            | (minor as u64 & 0xffff) << 32
+            | build as u64 & 0xffff_ffff;
+        VERSION.store(packed, Ordering::Relaxed);
+    }
+    ((packed >> 48) as u32 & 0x7fff, (packed >> 32) as u32 & 0xffff, packed as u32)
+}
+
+/// Issues a process-wide memory barrier: once this returns, every thread of
+/// the process has had its pending writes flushed, as if each had executed a
+/// full fence. This lets the frequently run side of an algorithm get away
+/// with compiler-only ordering while the rare side pays for both.
+///
+/// Before Vista `FlushProcessWriteBuffers` doesn't exist and this only fences
+/// the calling thread, which does *not* order the other threads. Algorithms
+/// built on this must give their light side a real fence on such systems.
+#[allow(dead_code)]
+pub(crate) unsafe fn flush_process_write_buffers() {
+    c::FlushProcessWriteBuffers()
+}
+
 // On Windows, use the processor-specific __fastfail mechanism.  In Windows 8
 // and later, this will terminate the process immediately without running any
 // in-process exception handlers.  In earlier versions of Windows, this
@@ -19,20 +19,58 @@
 //! CriticalSection is used and we keep track of who's holding the mutex to
 //! detect recursive locks.
 
+#[cfg(test)]
+use crate::cell::Cell;
 use crate::cell::UnsafeCell;
-use crate::mem::{self, MaybeUninit};
+use crate::mem::MaybeUninit;
 use crate::sync::atomic::{AtomicUsize, Ordering};
 use crate::sys::c;
 use crate::sys::compat;
+use crate::sys::rwlock::{Backoff, RawSrwLock};
+use crate::time::{Duration, Instant};
+#[cfg(feature = "lock_contention_hook")]
+use crate::sys::contention;
+#[cfg(all(debug_assertions, feature = "lock_held_tracking"))]
+use crate::sys::held;
+
+#[cfg(test)]
+mod tests;
+
+// How many `ReentrantMutex`es `remutex` has allocated on the current thread,
+// and how many of those it freed again after losing the race to publish one,
+// so that the tests can check the losers don't leak.
+#[cfg(test)]
+thread_local!(static REMUTEX_COUNTS: Cell<(usize, usize)> = Cell::new((0, 0)));
+
+#[cfg(test)]
+fn count_remutex(allocated: usize, freed: usize) {
+    REMUTEX_COUNTS.with(|counts| {
+        let (a, f) = counts.get();
+        counts.set((a + allocated, f + freed));
+    });
+}
 
 pub struct Mutex {
-    lock: AtomicUsize,
+    // Either an SRWLOCK or, with `Kind::CriticalSection`, a pointer to a boxed
+    // `ReentrantMutex` (0 until the first lock).
+    lock: RawSrwLock,
     held: UnsafeCell<bool>,
 }
 
 unsafe impl Send for Mutex {}
 unsafe impl Sync for Mutex {}
 
+/// The outcome of `Mutex::lock_alertable`.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertableLock {
+    /// The mutex is now held by the caller.
+    Acquired,
+    /// An APC ran on this thread before the mutex could be acquired; the
+    /// mutex is not held.
+    Interrupted,
+}
+
 #[derive(Clone, Copy)]
 enum Kind {
     SRWLock = 1,
@@ -41,37 +79,43 @@ enum Kind {
 
 #[inline]
 pub unsafe fn raw(m: &Mutex) -> c::PSRWLOCK {
-    debug_assert!(mem::size_of::<c::SRWLOCK>() <= mem::size_of_val(&m.lock));
-    &m.lock as *const _ as *mut _
+    m.lock.as_ptr()
 }
 
 impl Mutex {
     pub const fn new() -> Mutex {
-        Mutex {
-            // This works because SRWLOCK_INIT is 0 (wrapped in a struct), so we are also properly
-            // initializing an SRWLOCK here.
-            lock: AtomicUsize::new(0),
-            held: UnsafeCell::new(false),
-        }
+        Mutex { lock: RawSrwLock::new(), held: UnsafeCell::new(false) }
     }
     #[inline]
     pub unsafe fn init(&mut self) {}
     pub unsafe fn lock(&self) {
         match kind() {
-            Kind::SRWLock => c::AcquireSRWLockExclusive(raw(self)),
+            #[cfg(not(feature = "lock_contention_hook"))]
+            Kind::SRWLock => self.lock.acquire_exclusive(),
+            #[cfg(feature = "lock_contention_hook")]
+            Kind::SRWLock => contention::acquire(
+                "Mutex::lock",
+                || self.lock.try_acquire_exclusive(),
+                || self.lock.acquire_exclusive(),
+            ),
             Kind::CriticalSection => {
                 let re = self.remutex();
+                #[cfg(not(feature = "lock_contention_hook"))]
                 (*re).lock();
+                #[cfg(feature = "lock_contention_hook")]
+                contention::acquire("Mutex::lock", || (*re).try_lock(), || (*re).lock());
                 if !self.flag_locked() {
                     (*re).unlock();
                     panic!("cannot recursively lock a mutex");
                 }
             }
         }
+        #[cfg(all(debug_assertions, feature = "lock_held_tracking"))]
+        held::acquired();
     }
     pub unsafe fn try_lock(&self) -> bool {
-        match kind() {
-            Kind::SRWLock => c::TryAcquireSRWLockExclusive(raw(self)) != 0,
+        let acquired = match kind() {
+            Kind::SRWLock => self.lock.try_acquire_exclusive(),
             Kind::CriticalSection => {
                 let re = self.remutex();
                 if !(*re).try_lock() {
@@ -83,19 +127,47 @@ impl Mutex {
                     false
                 }
             }
+        };
+        #[cfg(all(debug_assertions, feature = "lock_held_tracking"))]
+        {
+            if acquired {
+                held::acquired();
+            }
+        }
+        acquired
+    }
+    /// Acquires the mutex like `lock`, but waits in an alertable state so that
+    /// an APC queued to this thread (see `QueueUserAPC`) interrupts the wait.
+    ///
+    /// Neither SRWLock nor CriticalSection acquisition is alertable, so this
+    /// polls `try_lock` with `SleepEx` in between. It is meant for watchdog
+    /// and shutdown paths that must not hang, not for hot locks. As with
+    /// `lock`, trying to acquire a mutex the thread already holds never
+    /// succeeds.
+    #[allow(dead_code)]
+    pub unsafe fn lock_alertable(&self) -> AlertableLock {
+        loop {
+            if self.try_lock() {
+                return AlertableLock::Acquired;
+            }
+            if c::SleepEx(1, c::TRUE) == c::WAIT_IO_COMPLETION {
+                return AlertableLock::Interrupted;
+            }
         }
     }
     pub unsafe fn unlock(&self) {
         *self.held.get() = false;
         match kind() {
-            Kind::SRWLock => c::ReleaseSRWLockExclusive(raw(self)),
+            Kind::SRWLock => self.lock.release_exclusive(),
             Kind::CriticalSection => (*self.remutex()).unlock(),
         }
+        #[cfg(all(debug_assertions, feature = "lock_held_tracking"))]
+        held::released();
     }
     pub unsafe fn destroy(&self) {
         match kind() {
             Kind::SRWLock => {}
-            Kind::CriticalSection => match self.lock.load(Ordering::SeqCst) {
+            Kind::CriticalSection => match self.lock.word().load(Ordering::SeqCst) {
                 0 => {}
                 n => {
                     Box::from_raw(n as *mut ReentrantMutex).destroy();
@@ -104,18 +176,31 @@ impl Mutex {
         }
     }
 
+    // Lazily allocates the CriticalSection fallback. Threads racing on the
+    // first lock each build a `ReentrantMutex`; exactly one wins the CAS and
+    // publishes it, and every loser destroys and frees its own allocation
+    // before using the winner's, so nothing leaks or is freed twice.
+    //
+    // The pointer must be published with release semantics (so the
+    // `InitializeCriticalSection` is visible) and read with acquire semantics;
+    // `SeqCst` is stronger than needed but this only runs on systems without
+    // SRWLock, where it is not worth weakening.
     unsafe fn remutex(&self) -> *mut ReentrantMutex {
-        match self.lock.load(Ordering::SeqCst) {
+        match self.lock.word().load(Ordering::SeqCst) {
             0 => {}
             n => return n as *mut _,
         }
         let re = box ReentrantMutex::uninitialized();
         re.init();
         let re = Box::into_raw(re);
-        match self.lock.compare_and_swap(0, re as usize, Ordering::SeqCst) {
+        #[cfg(test)]
+        count_remutex(1, 0);
+        match self.lock.word().compare_and_swap(0, re as usize, Ordering::SeqCst) {
             0 => re,
             n => {
                 Box::from_raw(re).destroy();
+                #[cfg(test)]
+                count_remutex(0, 1);
                 n as *mut _
             }
         }
@@ -131,6 +216,51 @@ impl Mutex {
     }
 }
 
+/// A mutex that always uses SRWLock, skipping the `kind()` dispatch that
+/// `Mutex` performs on every operation.
+///
+/// Prefer `Mutex` unless the program is known to only run on Windows Vista or
+/// later and the lock is hot enough for the dispatch to matter; constructing
+/// an `SrwMutex` on a system without SRWLock panics.
+#[allow(dead_code)]
+pub struct SrwMutex {
+    lock: RawSrwLock,
+}
+
+unsafe impl Send for SrwMutex {}
+unsafe impl Sync for SrwMutex {}
+
+#[allow(dead_code)]
+impl SrwMutex {
+    pub fn new() -> SrwMutex {
+        assert!(matches!(kind(), Kind::SRWLock), "SRWLock is not available on this system");
+        SrwMutex { lock: RawSrwLock::new() }
+    }
+    #[inline]
+    pub unsafe fn lock(&self) {
+        self.lock.acquire_exclusive()
+    }
+    #[inline]
+    pub unsafe fn try_lock(&self) -> bool {
+        self.lock.try_acquire_exclusive()
+    }
+    #[inline]
+    pub unsafe fn unlock(&self) {
+        self.lock.release_exclusive()
+    }
+}
+
+/// Returns whether mutexes use SRWLock, i.e. whether the CriticalSection
+/// fallback for systems older than Vista is never taken. Programs that
+/// require a newer system can check this at startup to fail fast.
+#[allow(dead_code)]
+pub fn srwlock_available() -> bool {
+    match kind() {
+        Kind::SRWLock => true,
+        Kind::CriticalSection => false,
+    }
+}
+
 fn kind() -> Kind {
     static KIND: AtomicUsize = AtomicUsize::new(0);
 
@@ -151,6 +281,11 @@ fn kind() -> Kind {
 
 pub struct ReentrantMutex {
     inner: UnsafeCell<MaybeUninit<c::CRITICAL_SECTION>>,
+    // Calling `InitializeCriticalSection` twice on the same storage is
+    // undefined and leaks the first critical section, so debug builds check
+    // that `init` only runs once.
+    #[cfg(debug_assertions)]
+    initialized: UnsafeCell<bool>,
 }
 
 unsafe impl Send for ReentrantMutex {}
@@ -158,27 +293,74 @@ unsafe impl Sync for ReentrantMutex {}
 
 impl ReentrantMutex {
     pub const fn uninitialized() -> ReentrantMutex {
-        ReentrantMutex { inner: UnsafeCell::new(MaybeUninit::uninit()) }
+        ReentrantMutex {
+            inner: UnsafeCell::new(MaybeUninit::uninit()),
+            #[cfg(debug_assertions)]
+            initialized: UnsafeCell::new(false),
+        }
     }
 
     pub unsafe fn init(&self) {
-        c::InitializeCriticalSection((&mut *self.inner.get()).as_mut_ptr());
+        #[cfg(debug_assertions)]
+        {
+            assert!(!*self.initialized.get(), "ReentrantMutex::init called twice");
+            *self.initialized.get() = true;
+        }
+        c::InitializeCriticalSection(self.raw());
+    }
+
+    /// The underlying `CRITICAL_SECTION`, for passing to Windows APIs such as
+    /// `SleepConditionVariableCS`. It is only usable between `init` and
+    /// `destroy`.
+    #[inline]
+    pub unsafe fn raw(&self) -> *mut c::CRITICAL_SECTION {
+        (&mut *self.inner.get()).as_mut_ptr()
     }
 
     pub unsafe fn lock(&self) {
-        c::EnterCriticalSection((&mut *self.inner.get()).as_mut_ptr());
+        c::EnterCriticalSection(self.raw());
     }
 
     #[inline]
     pub unsafe fn try_lock(&self) -> bool {
-        c::TryEnterCriticalSection((&mut *self.inner.get()).as_mut_ptr()) != 0
+        c::TryEnterCriticalSection(self.raw()) != 0
+    }
+
+    /// Tries to acquire the mutex until `dur` has passed, returning whether it
+    /// was acquired.
+    ///
+    /// Like `try_lock` this succeeds immediately if the current thread already
+    /// holds the mutex. A zero (or already elapsed) `dur` makes exactly one
+    /// attempt. In between attempts the thread backs off with `Backoff`, whose
+    /// sleeps are capped at the time left, so the deadline is kept closely
+    /// where the system supports high resolution timers.
+    #[allow(dead_code)]
+    pub unsafe fn try_lock_for(&self, dur: Duration) -> bool {
+        let deadline = match Instant::now().checked_add(dur) {
+            Some(deadline) => deadline,
+            None => {
+                self.lock();
+                return true;
+            }
+        };
+        let mut backoff = Backoff::new();
+        loop {
+            if self.try_lock() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            backoff.snooze_within(deadline - now);
+        }
     }
 
     pub unsafe fn unlock(&self) {
-        c::LeaveCriticalSection((&mut *self.inner.get()).as_mut_ptr());
+        c::LeaveCriticalSection(self.raw());
     }
 
     pub unsafe fn destroy(&self) {
-        c::DeleteCriticalSection((&mut *self.inner.get()).as_mut_ptr());
+        c::DeleteCriticalSection(self.raw());
     }
 }
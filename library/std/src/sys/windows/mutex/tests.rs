@@ -0,0 +1,174 @@
+use super::{ReentrantMutex, SrwMutex};
+
+#[test]
+fn srw_mutex_excludes() {
+    let m = SrwMutex::new();
+    unsafe {
+        m.lock();
+        assert!(!m.try_lock());
+        m.unlock();
+        assert!(m.try_lock());
+        m.unlock();
+    }
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "ReentrantMutex::init called twice")]
+fn reentrant_mutex_double_init_panics() {
+    let m = ReentrantMutex::uninitialized();
+    unsafe {
+        m.init();
+        m.init();
+    }
+}
+
+// Models the race in `Mutex::remutex`: every thread must come away with the
+// same CriticalSection, with the losers' allocations already freed.
+#[test]
+fn remutex_race_agrees_on_one_allocation() {
+    use super::{Mutex, REMUTEX_COUNTS};
+    use crate::sync::{Arc, Barrier};
+    use crate::thread;
+
+    const THREADS: usize = 8;
+
+    for _ in 0..20 {
+        let m = Arc::new(Mutex::new());
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let m = m.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    let p = unsafe { m.remutex() as usize };
+                    (p, REMUTEX_COUNTS.with(|counts| counts.get()))
+                })
+            })
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        let ptrs: Vec<usize> = results.iter().map(|&(p, _)| p).collect();
+        assert!(ptrs.iter().all(|&p| p == ptrs[0]));
+        // Every allocation but the published one was freed by its loser.
+        let allocated: usize = results.iter().map(|&(_, (a, _))| a).sum();
+        let freed: usize = results.iter().map(|&(_, (_, f))| f).sum();
+        assert!(allocated >= 1);
+        assert_eq!(freed, allocated - 1);
+
+        // `destroy` only frees the fallback when `kind()` picked it, so free
+        // the winner by hand.
+        unsafe { Box::from_raw(ptrs[0] as *mut ReentrantMutex).destroy() };
+    }
+}
+
+#[test]
+fn apc_interrupts_alertable_lock() {
+    use super::{AlertableLock, Mutex};
+    use crate::os::windows::io::AsRawHandle;
+    use crate::sync::mpsc::channel;
+    use crate::sys::c;
+    use crate::thread;
+
+    static M: Mutex = Mutex::new();
+
+    unsafe extern "system" fn wake(_: c::ULONG_PTR) {}
+
+    unsafe {
+        M.lock();
+        // An APC queued before the thread is up runs during its startup, and
+        // `lock_alertable` would then poll forever. Once the thread is
+        // running, a queued APC stays pending until its next alertable wait.
+        let (started_tx, started_rx) = channel();
+        let waiter = thread::spawn(move || {
+            started_tx.send(()).unwrap();
+            M.lock_alertable()
+        });
+        started_rx.recv().unwrap();
+        assert!(c::QueueUserAPC(Some(wake), waiter.as_raw_handle() as c::HANDLE, 0) != 0);
+        assert_eq!(waiter.join().unwrap(), AlertableLock::Interrupted);
+        M.unlock();
+
+        assert_eq!(M.lock_alertable(), AlertableLock::Acquired);
+        M.unlock();
+    }
+}
+
+#[test]
+fn reentrant_try_lock_for() {
+    use crate::sync::Arc;
+    use crate::thread;
+    use crate::time::{Duration, Instant};
+
+    let m = Arc::new(ReentrantMutex::uninitialized());
+    unsafe {
+        m.init();
+        m.lock();
+        // Already held by this thread, so even a zero wait succeeds.
+        assert!(m.try_lock_for(Duration::from_secs(0)));
+        m.unlock();
+
+        let other = m.clone();
+        let timed_out = thread::spawn(move || {
+            let start = Instant::now();
+            let acquired = other.try_lock_for(Duration::from_millis(50));
+            (acquired, start.elapsed())
+        });
+        let (acquired, waited) = timed_out.join().unwrap();
+        assert!(!acquired);
+        assert!(waited >= Duration::from_millis(50));
+
+        let other = m.clone();
+        let immediate = thread::spawn(move || other.try_lock_for(Duration::from_secs(0)));
+        assert!(!immediate.join().unwrap());
+
+        m.unlock();
+        m.destroy();
+    }
+}
+
+#[cfg(all(debug_assertions, feature = "lock_held_tracking"))]
+#[test]
+fn held_lock_count_follows_lock_and_unlock() {
+    use super::Mutex;
+    use crate::sys::held::held_lock_count;
+
+    let a = Mutex::new();
+    let b = Mutex::new();
+    let base = held_lock_count();
+    unsafe {
+        a.lock();
+        assert_eq!(held_lock_count(), base + 1);
+        assert!(b.try_lock());
+        assert_eq!(held_lock_count(), base + 2);
+        assert!(!b.try_lock());
+        assert_eq!(held_lock_count(), base + 2);
+        b.unlock();
+        a.unlock();
+    }
+    assert_eq!(held_lock_count(), base);
+}
+
+#[test]
+fn reentrant_mutex_raw_points_at_its_storage() {
+    use crate::sys::c;
+
+    let m = ReentrantMutex::uninitialized();
+    unsafe {
+        m.init();
+        assert_eq!(m.raw(), m.inner.get() as *mut c::CRITICAL_SECTION);
+        c::EnterCriticalSection(m.raw());
+        // Entering through the raw pointer is the same lock, so recursion is
+        // allowed from this thread.
+        assert!(m.try_lock());
+        m.unlock();
+        c::LeaveCriticalSection(m.raw());
+        m.destroy();
+    }
+}
+
+// Every OS we test on (Windows 7 and later) has SRWLock.
+#[test]
+fn srwlock_is_available() {
+    assert!(super::srwlock_available());
+}
@@ -1,40 +1,597 @@
-use crate::cell::UnsafeCell;
+use crate::cell::{RefCell, UnsafeCell};
+use crate::cmp;
+use crate::fmt;
+use crate::marker::PhantomData;
+use crate::mem::{self, MaybeUninit};
+use crate::ops::{Deref, DerefMut};
+use crate::ptr;
+#[cfg(any(feature = "lock_stats", feature = "lock_latency"))]
+use crate::sync::atomic::AtomicU64;
+use crate::sync::atomic::{fence, spin_loop_hint, AtomicBool, AtomicU8, AtomicUsize, Ordering};
 use crate::sys::c;
+#[cfg(feature = "lock_contention_hook")]
+use crate::sys::contention;
+use crate::sys::thread::high_res_sleep;
+use crate::sys::time::perf_counter;
+use crate::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests;
+
+/// An SRWLOCK stored in a pointer-sized atomic word.
+///
+/// `SRWLOCK_INIT` is all zeroes, so a zeroed word is already a valid,
+/// initialized and unlocked SRWLOCK. This is what lets `new` be a `const fn`
+/// with no separate initialization step, and what lets `Mutex` reuse the same
+/// word to point at its CriticalSection fallback on systems without SRWLock.
+pub struct RawSrwLock {
+    inner: AtomicUsize,
+}
+
+impl RawSrwLock {
+    pub const fn new() -> RawSrwLock {
+        RawSrwLock { inner: AtomicUsize::new(0) }
+    }
+    /// The storage word, for callers that keep something other than an
+    /// SRWLOCK in it (see `Mutex`).
+    #[inline]
+    pub fn word(&self) -> &AtomicUsize {
+        &self.inner
+    }
+    #[inline]
+    pub fn as_ptr(&self) -> c::PSRWLOCK {
+        debug_assert!(mem::size_of::<c::SRWLOCK>() <= mem::size_of_val(&self.inner));
+        &self.inner as *const _ as *mut _
+    }
+    #[inline]
+    pub unsafe fn acquire_shared(&self) {
+        c::AcquireSRWLockShared(self.as_ptr())
+    }
+    #[inline]
+    pub unsafe fn try_acquire_shared(&self) -> bool {
+        c::TryAcquireSRWLockShared(self.as_ptr()) != 0
+    }
+    #[inline]
+    pub unsafe fn release_shared(&self) {
+        c::ReleaseSRWLockShared(self.as_ptr())
+    }
+    #[inline]
+    pub unsafe fn acquire_exclusive(&self) {
+        c::AcquireSRWLockExclusive(self.as_ptr())
+    }
+    #[inline]
+    pub unsafe fn try_acquire_exclusive(&self) -> bool {
+        c::TryAcquireSRWLockExclusive(self.as_ptr()) != 0
+    }
+    #[inline]
+    pub unsafe fn release_exclusive(&self) {
+        c::ReleaseSRWLockExclusive(self.as_ptr())
+    }
+}
 
 pub struct RWLock {
-    inner: UnsafeCell<c::SRWLOCK>,
+    // In debug builds the lock word is surrounded by guard words that are
+    // checked on every operation, to catch the lock being overwritten.
+    #[cfg(debug_assertions)]
+    guard_before: usize,
+    inner: RawSrwLock,
+    #[cfg(debug_assertions)]
+    guard_after: usize,
+    #[cfg(feature = "lock_stats")]
+    stats: Counters,
+    #[cfg(feature = "lock_latency")]
+    latency: [AtomicU64; LATENCY_BUCKETS],
+}
+
+/// A snapshot of how often an `RWLock` has been acquired and released.
+///
+/// Once all users of a lock are done, mismatched acquire and release counts
+/// point at a leaked guard.
+///
+/// The `blocked` counts say how often `read` and `write` couldn't acquire the
+/// lock straight away and had to wait. SRWLock doesn't say who is holding
+/// it, so `writer_blocked_by_readers` counts writers that waited for anyone,
+/// readers or another writer, and `reader_blocked_by_writer` includes readers
+/// held back by a writer that was only queued.
+#[cfg(feature = "lock_stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockStats {
+    pub reads_acquired: u64,
+    pub reads_released: u64,
+    pub writes_acquired: u64,
+    pub writes_released: u64,
+    pub writer_blocked_by_readers: u64,
+    pub reader_blocked_by_writer: u64,
 }
 
+#[cfg(feature = "lock_stats")]
+struct Counters {
+    reads_acquired: AtomicU64,
+    reads_released: AtomicU64,
+    writes_acquired: AtomicU64,
+    writes_released: AtomicU64,
+    writer_blocked_by_readers: AtomicU64,
+    reader_blocked_by_writer: AtomicU64,
+}
+
+#[cfg(feature = "lock_stats")]
+impl Counters {
+    const fn new() -> Counters {
+        Counters {
+            reads_acquired: AtomicU64::new(0),
+            reads_released: AtomicU64::new(0),
+            writes_acquired: AtomicU64::new(0),
+            writes_released: AtomicU64::new(0),
+            writer_blocked_by_readers: AtomicU64::new(0),
+            reader_blocked_by_writer: AtomicU64::new(0),
+        }
+    }
+}
+
+// Bumps one of the `lock_stats` counters; compiles to nothing without the
+// feature.
+macro_rules! count {
+    ($lock:expr, $counter:ident) => {
+        #[cfg(feature = "lock_stats")]
+        $lock.stats.$counter.fetch_add(1, crate::sync::atomic::Ordering::Relaxed);
+    };
+}
+
+/// The number of buckets in `RWLock::latency_buckets`.
+#[cfg(feature = "lock_latency")]
+pub const LATENCY_BUCKETS: usize = 8;
+
+// Exclusive upper bounds of all but the last bucket, in nanoseconds: 1us,
+// 10us, 100us, 1ms, 10ms, 100ms and 1s. The last bucket takes the rest.
+#[cfg(feature = "lock_latency")]
+const LATENCY_BOUNDS: [u64; LATENCY_BUCKETS - 1] =
+    [1_000, 10_000, 100_000, 1_000_000, 10_000_000, 100_000_000, 1_000_000_000];
+
+thread_local! {
+    // The locks this thread holds through `RWLock::read_recursive`, by
+    // address, with how many times it holds each.
+    static READ_DEPTHS: RefCell<Vec<(usize, usize)>> = RefCell::new(Vec::new());
+}
+
+#[cfg(debug_assertions)]
+const GUARD: usize = 0x5257_4c6b; // "RWLk"
+
 unsafe impl Send for RWLock {}
 unsafe impl Sync for RWLock {}
 
+// An SRWLOCK doesn't expose whether it is held or by how many readers, and
+// probing it could deadlock a debugger inspecting a hung process, so the
+// state is reported as opaque.
+impl fmt::Debug for RWLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RWLock { <srwlock, opaque> }")
+    }
+}
+
 impl RWLock {
     pub const fn new() -> RWLock {
-        RWLock { inner: UnsafeCell::new(c::SRWLOCK_INIT) }
+        RWLock {
+            #[cfg(debug_assertions)]
+            guard_before: GUARD,
+            inner: RawSrwLock::new(),
+            #[cfg(debug_assertions)]
+            guard_after: GUARD,
+            #[cfg(feature = "lock_stats")]
+            stats: Counters::new(),
+            #[cfg(feature = "lock_latency")]
+            latency: [
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+            ],
+        }
+    }
+    /// Initializes an `RWLock` in place at `ptr`, producing exactly what `new`
+    /// does, for locks embedded in memory the caller already owns.
+    ///
+    /// This must run before any other operation on the lock, and `ptr` must be
+    /// valid for writes and suitably aligned.
+    #[allow(dead_code)]
+    pub unsafe fn init_in_place(ptr: *mut RWLock) {
+        ptr::write(ptr, RWLock::new())
+    }
+    #[inline]
+    fn check_guards(&self) {
+        #[cfg(debug_assertions)]
+        {
+            if self.guard_before != GUARD || self.guard_after != GUARD {
+                panic!("RWLock storage was corrupted (guard words overwritten)");
+            }
+        }
     }
     #[inline]
     pub unsafe fn read(&self) {
-        c::AcquireSRWLockShared(self.inner.get())
+        self.check_guards();
+        self.acquire(
+            "RWLock::read",
+            || self.inner.try_acquire_shared(),
+            || {
+                count!(self, reader_blocked_by_writer);
+                self.inner.acquire_shared()
+            },
+        );
+        count!(self, reads_acquired);
     }
     #[inline]
     pub unsafe fn try_read(&self) -> bool {
-        c::TryAcquireSRWLockShared(self.inner.get()) != 0
+        self.check_guards();
+        let acquired = self.inner.try_acquire_shared();
+        if acquired {
+            count!(self, reads_acquired);
+        }
+        acquired
     }
     #[inline]
     pub unsafe fn write(&self) {
-        c::AcquireSRWLockExclusive(self.inner.get())
+        self.check_guards();
+        self.acquire(
+            "RWLock::write",
+            || self.inner.try_acquire_exclusive(),
+            || {
+                count!(self, writer_blocked_by_readers);
+                self.inner.acquire_exclusive()
+            },
+        );
+        count!(self, writes_acquired);
+    }
+    // Blocks in `acquire`, first trying `try_acquire` if one of the features
+    // that track contended acquisitions is enabled.
+    #[inline]
+    #[allow(unused_variables)]
+    unsafe fn acquire(
+        &self,
+        what: &'static str,
+        try_acquire: impl FnOnce() -> bool,
+        acquire: impl FnOnce(),
+    ) {
+        #[cfg(feature = "lock_latency")]
+        let acquire = move || self.timed(acquire);
+        #[cfg(feature = "lock_contention_hook")]
+        contention::acquire(what, try_acquire, acquire);
+        #[cfg(all(
+            any(feature = "lock_latency", feature = "lock_stats"),
+            not(feature = "lock_contention_hook")
+        ))]
+        {
+            if !try_acquire() {
+                acquire()
+            }
+        }
+        #[cfg(not(any(
+            feature = "lock_latency",
+            feature = "lock_stats",
+            feature = "lock_contention_hook"
+        )))]
+        acquire();
+    }
+    // Runs the blocking `acquire`, recording how long it took.
+    #[cfg(feature = "lock_latency")]
+    fn timed(&self, acquire: impl FnOnce()) {
+        let start = Instant::now();
+        acquire();
+        let waited = start.elapsed().as_nanos();
+        let bucket = LATENCY_BOUNDS
+            .iter()
+            .position(|&bound| waited < bound as u128)
+            .unwrap_or(LATENCY_BUCKETS - 1);
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
     }
     #[inline]
     pub unsafe fn try_write(&self) -> bool {
-        c::TryAcquireSRWLockExclusive(self.inner.get()) != 0
+        self.check_guards();
+        let acquired = self.inner.try_acquire_exclusive();
+        if acquired {
+            count!(self, writes_acquired);
+        }
+        acquired
+    }
+    /// Like `try_read`, but retries up to `spins` more times, backing off with
+    /// `Backoff` between attempts, before giving up.
+    #[allow(dead_code)]
+    pub unsafe fn try_read_spin(&self, spins: u32) -> bool {
+        spin(spins, || self.try_read())
+    }
+    /// Like `try_write`, but retries up to `spins` more times, backing off
+    /// with `Backoff` between attempts, before giving up.
+    #[allow(dead_code)]
+    pub unsafe fn try_write_spin(&self, spins: u32) -> bool {
+        spin(spins, || self.try_write())
+    }
+    /// Like `try_write`, but keeps retrying for up to `ns` nanoseconds of wall
+    /// clock time before giving up, which unlike a retry count costs about
+    /// the same on every machine.
+    ///
+    /// A budget this short is spent spinning on the performance counter, with
+    /// a spin-loop hint between attempts; yielding or sleeping would overshoot
+    /// it. On a single CPU only one attempt is made.
+    #[allow(dead_code)]
+    pub unsafe fn try_write_spin_ns(&self, ns: u64) -> bool {
+        if self.try_write() {
+            return true;
+        }
+        if crate::sys::ncpus() == 1 {
+            return false;
+        }
+        let deadline = perf_counter::deadline_after_nanos(ns);
+        loop {
+            spin_loop_hint();
+            if self.try_write() {
+                return true;
+            }
+            if perf_counter::query() >= deadline {
+                return false;
+            }
+        }
+    }
+    /// Acquires the lock exclusively if that is possible right away, and for
+    /// reading otherwise (blocking if needed). The result says which was
+    /// acquired, and so which unlock the caller must use.
+    ///
+    /// The fallback to reading is not atomic with the failed write attempt:
+    /// a writer may get the lock in between, in which case this waits for it.
+    #[allow(dead_code)]
+    pub unsafe fn try_write_or_read(&self) -> Acquired {
+        if self.try_write() {
+            Acquired::Write
+        } else {
+            self.read();
+            Acquired::Read
+        }
+    }
+    /// Briefly gives up the exclusive lock held by the caller so that other
+    /// threads can get in, returning once it is held again.
+    ///
+    /// This releases the lock, yields the rest of the time slice with
+    /// `SwitchToThread`, and then blocks until it can acquire the lock again.
+    /// Anything else may have happened to the protected state in between, so
+    /// the caller must re-check its invariants afterwards.
+    #[allow(dead_code)]
+    pub unsafe fn write_yield(&self) {
+        self.write_unlock();
+        c::SwitchToThread();
+        self.write();
+    }
+    /// Tries to acquire the lock exclusively until `deadline`, giving up early
+    /// once `cancel` is set by another thread.
+    ///
+    /// Between attempts this checks `cancel` and backs off with `Backoff`,
+    /// which sleeps for at most a millisecond at a time, so a cancellation is
+    /// noticed within about that long. At least one attempt is made even if
+    /// `deadline` has already passed or `cancel` is already set.
+    #[allow(dead_code)]
+    pub unsafe fn try_write_until_cancel(
+        &self,
+        deadline: Instant,
+        cancel: &AtomicBool,
+    ) -> AcquireOutcome {
+        let mut backoff = Backoff::new();
+        loop {
+            if self.try_write() {
+                return AcquireOutcome::Acquired;
+            }
+            if cancel.load(Ordering::Relaxed) {
+                return AcquireOutcome::Canceled;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return AcquireOutcome::TimedOut;
+            }
+            backoff.snooze_within(deadline - now);
+        }
+    }
+    /// Attempts to convert a shared lock held by the caller into an exclusive
+    /// one, returning whether it succeeded.
+    ///
+    /// SRWLock has no native upgrade, so this releases the shared lock and
+    /// then tries to acquire it exclusively. The two steps are not atomic:
+    /// another writer may get in between, so anything observed under the
+    /// shared lock must be re-validated after a successful upgrade. If the
+    /// exclusive acquisition fails the shared lock is re-acquired, blocking if
+    /// needed, so either way the caller still holds the lock on return.
+    #[allow(dead_code)]
+    pub unsafe fn try_upgrade_shared_to_exclusive(&self) -> bool {
+        self.read_unlock();
+        if self.try_write() {
+            true
+        } else {
+            self.read();
+            false
+        }
+    }
+    /// In debug builds, panics if the lock is currently held by anyone. This
+    /// backs up code that relies on `&mut` access meaning nobody holds the
+    /// lock (like `RwLock::get_mut`).
+    ///
+    /// SRWLock can't be queried, so this briefly acquires and releases the
+    /// lock exclusively; it must not be called while the current thread holds
+    /// it expecting success.
+    #[allow(dead_code)]
+    pub unsafe fn debug_assert_unlocked(&self) {
+        if cfg!(debug_assertions) {
+            if !self.inner.try_acquire_exclusive() {
+                panic!("RWLock expected to be unlocked, but it is held");
+            }
+            self.inner.release_exclusive();
+        }
+    }
+    /// Returns whether the lock was held, in either mode, at the instant of
+    /// the call.
+    ///
+    /// SRWLock can't be queried, so this tries to acquire the lock exclusively
+    /// and immediately releases it again, which can perturb which waiter gets
+    /// the lock next. The answer is a racy snapshot for metrics only and must
+    /// not be used for synchronization.
+    #[allow(dead_code)]
+    pub unsafe fn probe_locked(&self) -> bool {
+        if self.inner.try_acquire_exclusive() {
+            self.inner.release_exclusive();
+            false
+        } else {
+            true
+        }
     }
     #[inline]
     pub unsafe fn read_unlock(&self) {
-        c::ReleaseSRWLockShared(self.inner.get())
+        self.check_guards();
+        count!(self, reads_released);
+        self.inner.release_shared()
+    }
+    /// Releases `n` shared holds on the lock at once, for teardown code that
+    /// tracked several of them.
+    ///
+    /// Each shared acquisition of an SRWLock must be matched by its own
+    /// release, so this is `n` calls to `read_unlock`. The lock can't report
+    /// how many readers it has, so the caller must actually hold `n` shared
+    /// locks.
+    #[allow(dead_code)]
+    pub unsafe fn read_unlock_n(&self, n: usize) {
+        for _ in 0..n {
+            self.read_unlock();
+        }
     }
     #[inline]
     pub unsafe fn write_unlock(&self) {
-        c::ReleaseSRWLockExclusive(self.inner.get())
+        self.check_guards();
+        count!(self, writes_released);
+        self.inner.release_exclusive()
+    }
+
+    /// Returns the acquire and release counts recorded so far.
+    #[cfg(feature = "lock_stats")]
+    #[allow(dead_code)]
+    pub fn stats(&self) -> LockStats {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        LockStats {
+            reads_acquired: load(&self.stats.reads_acquired),
+            reads_released: load(&self.stats.reads_released),
+            writes_acquired: load(&self.stats.writes_acquired),
+            writes_released: load(&self.stats.writes_released),
+            writer_blocked_by_readers: load(&self.stats.writer_blocked_by_readers),
+            reader_blocked_by_writer: load(&self.stats.reader_blocked_by_writer),
+        }
+    }
+
+    /// Returns how many blocking acquisitions of this lock waited for how
+    /// long, counted from just before blocking until the lock was acquired.
+    ///
+    /// Bucket `i` counts waits shorter than `10^i` microseconds, except the
+    /// last bucket, which counts everything from one second up. Acquisitions
+    /// that didn't have to block aren't recorded.
+    #[cfg(feature = "lock_latency")]
+    #[allow(dead_code)]
+    pub fn latency_buckets(&self) -> [u64; LATENCY_BUCKETS] {
+        let mut buckets = [0; LATENCY_BUCKETS];
+        for (bucket, count) in buckets.iter_mut().zip(&self.latency) {
+            *bucket = count.load(Ordering::Relaxed);
+        }
+        buckets
+    }
+
+    /// Returns whether `read` or `write` ever had to wait for this lock,
+    /// which is cheaper to check across many locks than their full stats.
+    #[cfg(feature = "lock_stats")]
+    #[allow(dead_code)]
+    pub fn ever_contended(&self) -> bool {
+        self.stats.writer_blocked_by_readers.load(Ordering::Relaxed) != 0
+            || self.stats.reader_blocked_by_writer.load(Ordering::Relaxed) != 0
+    }
+
+    /// Acquires the lock for reading, without touching the SRWLOCK if the
+    /// current thread already holds it through this method. Every call must be
+    /// paired with `read_unlock_recursive`.
+    ///
+    /// Re-acquiring an SRWLock for reading on a thread that already holds it
+    /// can deadlock: if a writer queued up in between, the nested acquisition
+    /// waits behind the writer, which waits for the outer read. Here only the
+    /// outermost call acquires the lock and nested ones just count, so a
+    /// queued writer can't get in between them. As with `RecursiveRWLock`,
+    /// holds taken through plain `read` aren't seen, and the depth is tracked
+    /// in a thread-local per lock.
+    #[allow(dead_code)]
+    pub unsafe fn read_recursive(&self) {
+        let key = self as *const RWLock as usize;
+        let nested = READ_DEPTHS.with(|depths| {
+            let mut depths = depths.borrow_mut();
+            match depths.iter_mut().find(|(lock, _)| *lock == key) {
+                Some((_, depth)) => {
+                    // Wrapping would make the next unlock release a lock that
+                    // is still held.
+                    *depth = depth.checked_add(1).expect("reader count overflow");
+                    true
+                }
+                None => {
+                    depths.push((key, 1));
+                    false
+                }
+            }
+        });
+        if !nested {
+            self.read();
+        }
+    }
+    #[allow(dead_code)]
+    pub unsafe fn read_unlock_recursive(&self) {
+        let key = self as *const RWLock as usize;
+        let outermost = READ_DEPTHS.with(|depths| {
+            let mut depths = depths.borrow_mut();
+            let i = depths
+                .iter()
+                .position(|(lock, _)| *lock == key)
+                .expect("read_unlock_recursive without read_recursive");
+            depths[i].1 -= 1;
+            if depths[i].1 == 0 {
+                depths.swap_remove(i);
+                true
+            } else {
+                false
+            }
+        });
+        if outermost {
+            self.read_unlock();
+        }
+    }
+
+    /// Acquires the lock exclusively and returns a ticket for releasing it,
+    /// for when the release happens on the far side of an FFI boundary.
+    ///
+    /// The lock stays held until the ticket is passed to
+    /// `release_write_raw`, which must happen on the acquiring thread: the
+    /// native side may hold on to the ticket, but has to hand it back on this
+    /// thread, since SRWLock requires an exclusive hold to be released by the
+    /// thread that acquired it. The ticket is the lock's address, so the lock
+    /// must not move or be destroyed in between.
+    #[allow(dead_code)]
+    pub unsafe fn acquire_write_raw(&self) -> WriteTicket {
+        self.write();
+        WriteTicket(self as *const RWLock as usize)
+    }
+    /// Releases the lock `ticket` was issued for by `acquire_write_raw`.
+    /// Each ticket may only be released once, and must be released on the
+    /// thread that acquired it.
+    #[allow(dead_code)]
+    pub unsafe fn release_write_raw(ticket: WriteTicket) {
+        (*(ticket.0 as *const RWLock)).write_unlock()
+    }
+
+    /// Mints a token standing for the caller's shared hold on this lock, so
+    /// that code further down can require proof that the lock is held.
+    ///
+    /// The token carries no runtime state and doesn't release anything when
+    /// dropped. It can only be created through this function, and it is up to
+    /// the caller to only do so while holding the lock for reading.
+    #[allow(dead_code)]
+    pub(crate) unsafe fn read_token(&self) -> ReadToken<'_> {
+        ReadToken { _lock: PhantomData }
     }
 
     #[inline]
@@ -42,3 +599,600 @@ impl RWLock {
         // ...
     }
 }
+
+/// An `RWLock` with a poison flag beside it, for a caller that tracks
+/// poisoning at this layer rather than through `sys_common::poison`.
+///
+/// The SRWLOCK word has no spare bits to borrow, so the flag needs a word of
+/// its own; keeping it in this wrapper means `RWLock` itself doesn't carry it.
+#[allow(dead_code)]
+pub struct PoisonRWLock {
+    lock: RWLock,
+    poisoned: AtomicBool,
+}
+
+impl PoisonRWLock {
+    #[allow(dead_code)]
+    pub const fn new() -> PoisonRWLock {
+        PoisonRWLock { lock: RWLock::new(), poisoned: AtomicBool::new(false) }
+    }
+    /// The underlying lock, for acquiring and releasing it without looking at
+    /// the flag.
+    #[allow(dead_code)]
+    pub fn inner(&self) -> &RWLock {
+        &self.lock
+    }
+
+    /// Marks the lock as poisoned, for a holder that panicked.
+    ///
+    /// The flag is only stored here, it doesn't change how the lock is
+    /// acquired or released; it stays set across lock and unlock until
+    /// `clear_poison` is called.
+    #[allow(dead_code)]
+    pub fn poison(&self) {
+        self.poisoned.store(true, Ordering::Relaxed);
+    }
+    #[allow(dead_code)]
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+    #[allow(dead_code)]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+    /// Acquires the lock exclusively, returning `Err` if it was poisoned.
+    ///
+    /// The lock is held on return either way, as with the guard inside
+    /// std's `PoisonError`, so the caller can recover the data and call
+    /// `clear_poison` before releasing it through `inner`.
+    #[allow(dead_code)]
+    pub unsafe fn write_or_poisoned(&self) -> Result<(), ()> {
+        self.lock.write();
+        if self.is_poisoned() {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Like `RWLock::try_read`, but also reports whether the lock was
+    /// poisoned, so that the caller can tell apart all the outcomes std's
+    /// `RwLock::try_read` distinguishes.
+    #[allow(dead_code)]
+    pub unsafe fn try_read_status(&self) -> TryStatus {
+        if !self.lock.try_read() {
+            TryStatus::WouldBlock
+        } else if self.is_poisoned() {
+            TryStatus::AcquiredPoisoned
+        } else {
+            TryStatus::Acquired
+        }
+    }
+    /// The exclusive counterpart of `try_read_status`.
+    #[allow(dead_code)]
+    pub unsafe fn try_write_status(&self) -> TryStatus {
+        if !self.lock.try_write() {
+            TryStatus::WouldBlock
+        } else if self.is_poisoned() {
+            TryStatus::AcquiredPoisoned
+        } else {
+            TryStatus::Acquired
+        }
+    }
+}
+
+/// An `RWLock` whose exclusive side can be re-entered by the thread holding
+/// it, see `lock_exclusive_recursive`.
+///
+/// The owner is tracked here rather than in `RWLock`, so that locks which are
+/// only ever taken through plain `write` and `read` don't carry it.
+#[allow(dead_code)]
+pub struct RecursiveRWLock {
+    lock: RWLock,
+    // The id of the thread holding the lock through
+    // `lock_exclusive_recursive` (0 if none), and how many times it has done so.
+    owner: AtomicUsize,
+    recursion: UnsafeCell<usize>,
+}
+
+unsafe impl Send for RecursiveRWLock {}
+unsafe impl Sync for RecursiveRWLock {}
+
+impl RecursiveRWLock {
+    #[allow(dead_code)]
+    pub const fn new() -> RecursiveRWLock {
+        RecursiveRWLock {
+            lock: RWLock::new(),
+            owner: AtomicUsize::new(0),
+            recursion: UnsafeCell::new(0),
+        }
+    }
+    /// The underlying lock, for holds that don't need to recurse.
+    #[allow(dead_code)]
+    pub fn inner(&self) -> &RWLock {
+        &self.lock
+    }
+
+    /// Acquires the lock exclusively, returning immediately if the current
+    /// thread already holds it through this method. Every call must be paired
+    /// with `unlock_exclusive_recursive`.
+    ///
+    /// The owner is only tracked for this pair of methods: a thread holding
+    /// the lock through plain `write` or `read` still deadlocks here.
+    #[allow(dead_code)]
+    pub unsafe fn lock_exclusive_recursive(&self) {
+        let me = c::GetCurrentThreadId() as usize;
+        // Relaxed is enough: only this thread ever stores its own id, so the
+        // load can't observe `me` unless this thread holds the lock. The
+        // hold can't be passed to another thread either, since SRWLock
+        // requires an exclusive hold to be released on the thread that
+        // acquired it.
+        if self.owner.load(Ordering::Relaxed) == me {
+            *self.recursion.get() += 1;
+            return;
+        }
+        self.lock.write();
+        self.owner.store(me, Ordering::Relaxed);
+        *self.recursion.get() = 1;
+    }
+    #[allow(dead_code)]
+    pub unsafe fn unlock_exclusive_recursive(&self) {
+        debug_assert_eq!(self.owner.load(Ordering::Relaxed), c::GetCurrentThreadId() as usize);
+        *self.recursion.get() -= 1;
+        if *self.recursion.get() == 0 {
+            self.owner.store(0, Ordering::Relaxed);
+            self.lock.write_unlock();
+        }
+    }
+}
+
+/// An exclusive hold on an `RWLock` that isn't tied to a lifetime, see
+/// `RWLock::acquire_write_raw`.
+///
+/// It converts to and from a plain integer so that it can be handed to native
+/// code and back.
+#[allow(dead_code)]
+#[derive(Debug, PartialEq, Eq)]
+pub struct WriteTicket(usize);
+
+#[allow(dead_code)]
+impl WriteTicket {
+    pub fn into_raw(self) -> usize {
+        self.0
+    }
+    /// Rebuilds a ticket from `into_raw`'s result.
+    pub unsafe fn from_raw(raw: usize) -> WriteTicket {
+        WriteTicket(raw)
+    }
+}
+
+/// The outcome of `RWLock::try_write_until_cancel`.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcquireOutcome {
+    /// The lock is now held exclusively.
+    Acquired,
+    /// The deadline passed first; the lock is not held.
+    TimedOut,
+    /// The cancel flag was set first; the lock is not held.
+    Canceled,
+}
+
+/// The outcome of `PoisonRWLock::try_read_status` and `try_write_status`.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryStatus {
+    /// The lock is now held.
+    Acquired,
+    /// The lock is held by someone else; nothing was acquired.
+    WouldBlock,
+    /// The lock is now held, but it was poisoned.
+    AcquiredPoisoned,
+}
+
+/// Which kind of access `RWLock::try_write_or_read` acquired.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Acquired {
+    /// The lock is held exclusively; release it with `write_unlock`.
+    Write,
+    /// The lock is held for reading; release it with `read_unlock`.
+    Read,
+}
+
+/// Acquires write access to both `a` and `b`, releasing both when the returned
+/// guard is dropped.
+///
+/// The locks are always taken in address order, so two threads locking the
+/// same pair with the arguments swapped can't deadlock each other. Panics if
+/// `a` and `b` are the same lock.
+#[allow(dead_code)]
+pub unsafe fn lock_both<'a>(a: &'a RWLock, b: &'a RWLock) -> BothWriteGuard<'a> {
+    assert!(!ptr::eq(a, b), "lock_both called with the same lock twice");
+    let (first, second) = if (a as *const RWLock) < (b as *const RWLock) { (a, b) } else { (b, a) };
+    first.write();
+    second.write();
+    BothWriteGuard { first, second }
+}
+
+/// Write access to two locks, see `lock_both`.
+pub struct BothWriteGuard<'a> {
+    first: &'a RWLock,
+    second: &'a RWLock,
+}
+
+impl Drop for BothWriteGuard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            self.second.write_unlock();
+            self.first.write_unlock();
+        }
+    }
+}
+
+/// Acquires `lock` exclusively, first trying up to `spins` more times without
+/// blocking (backing off with `Backoff` between attempts) before falling back
+/// to a blocking acquire.
+///
+/// `AcquireSRWLockExclusive` already spins briefly on its own before waiting,
+/// so this is only worth using where a caller has a better spin budget for
+/// its workload than the OS default.
+#[allow(dead_code)]
+pub(crate) unsafe fn srw_write_spin_then_block(lock: c::PSRWLOCK, spins: u32) {
+    if !spin(spins, || c::TryAcquireSRWLockExclusive(lock) != 0) {
+        c::AcquireSRWLockExclusive(lock)
+    }
+}
+
+// Retrying is pointless on a single CPU: the holder can't run, let alone
+// release the lock, while we spin.
+#[allow(dead_code)]
+fn spin(spins: u32, try_acquire: impl FnMut() -> bool) -> bool {
+    let spins = if crate::sys::ncpus() == 1 { 0 } else { spins };
+    spin_exactly(spins, try_acquire)
+}
+
+#[allow(dead_code)]
+fn spin_exactly(spins: u32, mut try_acquire: impl FnMut() -> bool) -> bool {
+    if try_acquire() {
+        return true;
+    }
+    let mut backoff = Backoff::new();
+    for _ in 0..spins {
+        backoff.spin();
+        if try_acquire() {
+            return true;
+        }
+    }
+    false
+}
+
+/// How a retry loop waits between attempts, escalating the longer it has been
+/// retrying: first a growing number of spin-loop hints (pause instructions),
+/// then yielding the time slice with `SwitchToThread`, then sleeping.
+///
+/// On a single CPU the pause phase is skipped, since the holder of whatever
+/// is being waited for can't run while this thread spins.
+///
+/// Only loops working towards a deadline go on to sleep; loops bounded by a
+/// number of attempts use `spin`, which stops escalating at yielding.
+pub(crate) struct Backoff {
+    step: u32,
+}
+
+// Steps below `PAUSE_STEPS` spin for `1 << step` hints, steps below
+// `YIELD_STEPS` yield, and everything after that sleeps.
+const PAUSE_STEPS: u32 = 6;
+const YIELD_STEPS: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BackoffPhase {
+    Pause(u32),
+    Yield,
+    Sleep,
+}
+
+#[allow(dead_code)]
+impl Backoff {
+    pub(crate) fn new() -> Backoff {
+        Backoff { step: if crate::sys::ncpus() == 1 { PAUSE_STEPS } else { 0 } }
+    }
+    fn phase(&self) -> BackoffPhase {
+        if self.step < PAUSE_STEPS {
+            BackoffPhase::Pause(1 << self.step)
+        } else if self.step < YIELD_STEPS {
+            BackoffPhase::Yield
+        } else {
+            BackoffPhase::Sleep
+        }
+    }
+    /// Waits once, sleeping for a millisecond once the pause and yield phases
+    /// are over.
+    pub(crate) fn snooze(&mut self) {
+        self.snooze_within(Duration::from_millis(1))
+    }
+    /// Like `snooze`, but stays in the yield phase instead of moving on to
+    /// sleeping, for loops bounded by a number of attempts: a caller asking
+    /// for a few hundred spins doesn't expect to block for as many
+    /// milliseconds.
+    pub(crate) fn spin(&mut self) {
+        self.step = cmp::min(self.step, YIELD_STEPS - 1);
+        self.snooze();
+        self.step = cmp::min(self.step, YIELD_STEPS - 1);
+    }
+    /// Like `snooze`, but never sleeps for longer than `limit`, for loops
+    /// working towards a deadline.
+    pub(crate) fn snooze_within(&mut self, limit: Duration) {
+        match self.phase() {
+            BackoffPhase::Pause(hints) => {
+                for _ in 0..hints {
+                    spin_loop_hint();
+                }
+            }
+            BackoffPhase::Yield => unsafe {
+                c::SwitchToThread();
+            },
+            BackoffPhase::Sleep => high_res_sleep(cmp::min(limit, Duration::from_millis(1))),
+        }
+        if self.step < YIELD_STEPS {
+            self.step += 1;
+        }
+    }
+}
+
+/// Proof that the holder has shared access to an `RWLock`, see `read_token`.
+///
+/// The private field keeps it from being constructed anywhere else.
+#[allow(dead_code)]
+pub(crate) struct ReadToken<'a> {
+    _lock: PhantomData<&'a RWLock>,
+}
+
+/// A reader-writer lock stored inline with the data it protects, so that the
+/// SRWLOCK word and the `T` share a cache line instead of the data living
+/// behind a separately allocated lock.
+///
+/// Unlike `RWLock` this is not `unsafe` to use: an SRWLOCK may be moved while
+/// it is unlocked, and the guards borrow the lock so it can't be moved while
+/// held. Since the Windows `RWLock` has no heap-allocated fallback state, there
+/// is nothing else to keep inline.
+#[allow(dead_code)]
+pub struct PackedRwLock<T> {
+    lock: RawSrwLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for PackedRwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for PackedRwLock<T> {}
+
+#[allow(dead_code)]
+impl<T> PackedRwLock<T> {
+    pub const fn new(data: T) -> PackedRwLock<T> {
+        PackedRwLock { lock: RawSrwLock::new(), data: UnsafeCell::new(data) }
+    }
+    pub fn read(&self) -> PackedReadGuard<'_, T> {
+        unsafe { self.lock.acquire_shared() };
+        PackedReadGuard { lock: self }
+    }
+    pub fn write(&self) -> PackedWriteGuard<'_, T> {
+        unsafe { self.lock.acquire_exclusive() };
+        PackedWriteGuard { lock: self }
+    }
+}
+
+pub struct PackedReadGuard<'a, T> {
+    lock: &'a PackedRwLock<T>,
+}
+
+impl<T> Deref for PackedReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for PackedReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.lock.release_shared() }
+    }
+}
+
+pub struct PackedWriteGuard<'a, T> {
+    lock: &'a PackedRwLock<T>,
+}
+
+impl<T> Deref for PackedWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for PackedWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for PackedWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.lock.release_exclusive() }
+    }
+}
+
+/// A lock for small, read-mostly `Copy` values, where readers never write to
+/// shared memory.
+///
+/// Writers serialize on an SRWLOCK and bump a sequence number before and
+/// after updating the value, so it is odd while a write is in progress.
+/// Readers copy the value out and retry if the sequence number was odd or
+/// changed while they were copying, so they may spin while a writer is
+/// active. Best suited to data like configuration that changes rarely and is
+/// read often; for anything else use `RWLock`.
+///
+/// The value is copied in and out a byte at a time through relaxed atomics,
+/// which would read any padding bytes in `T` as integers, so `read` and
+/// `write` are unsafe and require `T` to have none.
+#[allow(dead_code)]
+pub struct SeqRwLock<T> {
+    seq: AtomicUsize,
+    lock: RawSrwLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for SeqRwLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqRwLock<T> {}
+
+// `new` is in a block of its own because a `const fn` can't have trait bounds.
+#[allow(dead_code)]
+impl<T> SeqRwLock<T> {
+    pub const fn new(data: T) -> SeqRwLock<T> {
+        SeqRwLock { seq: AtomicUsize::new(0), lock: RawSrwLock::new(), data: UnsafeCell::new(data) }
+    }
+}
+
+#[allow(dead_code)]
+impl<T: Copy> SeqRwLock<T> {
+    fn bytes(&self) -> *const AtomicU8 {
+        self.data.get() as *const AtomicU8
+    }
+    /// Copies the value out, retrying until no write overlapped the copy.
+    ///
+    /// `T` must not contain any padding bytes.
+    pub unsafe fn read(&self) -> T {
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before & 1 != 0 {
+                spin_loop_hint();
+                continue;
+            }
+            // The copy may race with a writer and see a torn value, which the
+            // sequence check below throws away. Going through atomics keeps
+            // the race itself defined, where a plain or volatile read of
+            // memory that is being written would not be.
+            let mut value = MaybeUninit::<T>::uninit();
+            let out = value.as_mut_ptr() as *mut u8;
+            for i in 0..mem::size_of::<T>() {
+                *out.add(i) = (*self.bytes().add(i)).load(Ordering::Relaxed);
+            }
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == before {
+                return value.assume_init();
+            }
+        }
+    }
+    /// Replaces the value, waiting for any other writer to finish first.
+    ///
+    /// `T` must not contain any padding bytes.
+    pub unsafe fn write(&self, value: T) {
+        let bytes = &value as *const T as *const u8;
+        self.lock.acquire_exclusive();
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        for i in 0..mem::size_of::<T>() {
+            (*self.bytes().add(i)).store(*bytes.add(i), Ordering::Relaxed);
+        }
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+        self.lock.release_exclusive();
+    }
+}
+
+// Enough slots that threads rarely share one, without making writers scan
+// too far.
+const READER_SLOTS: usize = 16;
+
+// Padded to a cache line so that readers in different slots don't contend.
+#[repr(align(64))]
+struct ReaderSlot(AtomicUsize);
+
+/// A reader-writer lock for read-heavy data, where readers announce
+/// themselves in one of several per-thread slots instead of all updating the
+/// same lock word.
+///
+/// A reader increments its thread's slot and then checks that no writer is
+/// active; a writer takes an `RWLock` exclusively (to serialize with other
+/// writers), flags itself as active and then waits for every slot to drain.
+/// Readers therefore never touch a shared cache line unless a writer is
+/// around, while writes become more expensive: they scan all slots and spin
+/// until current readers leave. Readers that find a writer active wait for
+/// it on the `RWLock`.
+#[allow(dead_code)]
+pub struct DistributedRwLock {
+    writers: RWLock,
+    writing: AtomicBool,
+    readers: [ReaderSlot; READER_SLOTS],
+}
+
+#[allow(dead_code)]
+impl DistributedRwLock {
+    pub const fn new() -> DistributedRwLock {
+        DistributedRwLock {
+            writers: RWLock::new(),
+            writing: AtomicBool::new(false),
+            readers: [
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+                ReaderSlot(AtomicUsize::new(0)),
+            ],
+        }
+    }
+    // Thread ids are multiples of 4, so drop the low bits before picking a
+    // slot. The slot has to be the same at unlock, so this can't use the
+    // current processor.
+    fn slot(&self) -> &AtomicUsize {
+        let id = unsafe { c::GetCurrentThreadId() } as usize;
+        &self.readers[(id >> 2) % READER_SLOTS].0
+    }
+    /// Acquires the lock for reading.
+    ///
+    /// This is not reentrant: a thread that already holds a read lock must not
+    /// call this again while a writer may be waiting. The writer waits for the
+    /// outer read to leave its slot, while the nested read backs off and waits
+    /// for the writer, so the thread deadlocks.
+    pub unsafe fn read(&self) {
+        let slot = self.slot();
+        loop {
+            // SeqCst on both sides: either the writer sees this increment or
+            // this reader sees `writing`, never neither.
+            slot.fetch_add(1, Ordering::SeqCst);
+            if !self.writing.load(Ordering::SeqCst) {
+                return;
+            }
+            slot.fetch_sub(1, Ordering::SeqCst);
+            // Wait for the writer to finish.
+            self.writers.read();
+            self.writers.read_unlock();
+        }
+    }
+    pub unsafe fn read_unlock(&self) {
+        self.slot().fetch_sub(1, Ordering::Release);
+    }
+    pub unsafe fn write(&self) {
+        self.writers.write();
+        self.writing.store(true, Ordering::SeqCst);
+        let mut backoff = Backoff::new();
+        for slot in &self.readers {
+            while slot.0.load(Ordering::SeqCst) != 0 {
+                backoff.snooze();
+            }
+        }
+    }
+    pub unsafe fn write_unlock(&self) {
+        self.writing.store(false, Ordering::Release);
+        self.writers.write_unlock();
+    }
+}
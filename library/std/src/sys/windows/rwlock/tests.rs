@@ -0,0 +1,899 @@
+use super::{PoisonRWLock, RWLock, RecursiveRWLock};
+use crate::panic::{self, AssertUnwindSafe};
+
+/// Runs `f` while holding `lock` for writing, releasing the lock even if `f`
+/// panics so that one failing test can't leave the lock held for the next.
+fn with_write<R>(lock: &RWLock, f: impl FnOnce() -> R) -> R {
+    unsafe { lock.write() };
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    unsafe { lock.write_unlock() };
+    result.unwrap_or_else(|e| panic::resume_unwind(e))
+}
+
+/// Like `with_write`, but acquires shared access.
+fn with_read<R>(lock: &RWLock, f: impl FnOnce() -> R) -> R {
+    unsafe { lock.read() };
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    unsafe { lock.read_unlock() };
+    result.unwrap_or_else(|e| panic::resume_unwind(e))
+}
+
+#[test]
+fn write_excludes_everyone() {
+    let lock = RWLock::new();
+    with_write(&lock, || unsafe {
+        assert!(!lock.try_read());
+        assert!(!lock.try_write());
+    });
+    unsafe {
+        assert!(lock.try_write());
+        lock.write_unlock();
+    }
+}
+
+#[test]
+fn read_is_shared() {
+    let lock = RWLock::new();
+    with_read(&lock, || unsafe {
+        assert!(lock.try_read());
+        lock.read_unlock();
+        assert!(!lock.try_write());
+    });
+}
+
+#[test]
+fn released_after_panic() {
+    let lock = RWLock::new();
+    let r = panic::catch_unwind(AssertUnwindSafe(|| with_write(&lock, || panic!("boom"))));
+    assert!(r.is_err());
+    let r = panic::catch_unwind(AssertUnwindSafe(|| with_read(&lock, || panic!("boom"))));
+    assert!(r.is_err());
+    unsafe {
+        assert!(lock.try_write());
+        lock.write_unlock();
+    }
+}
+
+#[cfg(feature = "lock_contention_hook")]
+#[test]
+fn contention_hook_fires() {
+    use crate::sync::atomic::{AtomicUsize, Ordering};
+    use crate::sys::contention::set_contention_hook;
+    use crate::thread;
+
+    static LOCK: RWLock = RWLock::new();
+    static CONTENDED: AtomicUsize = AtomicUsize::new(0);
+
+    fn hook(what: &'static str) {
+        if what == "RWLock::read" {
+            CONTENDED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    set_contention_hook(hook);
+
+    unsafe { LOCK.write() };
+    let reader = thread::spawn(|| with_read(&LOCK, || ()));
+    while CONTENDED.load(Ordering::SeqCst) == 0 {
+        thread::yield_now();
+    }
+    unsafe { LOCK.write_unlock() };
+    reader.join().unwrap();
+}
+
+#[test]
+fn spin_gives_up_on_held_lock() {
+    let lock = RWLock::new();
+    with_write(&lock, || unsafe {
+        assert!(!lock.try_write_spin(100));
+        assert!(!lock.try_read_spin(100));
+    });
+    with_read(&lock, || unsafe {
+        assert!(!lock.try_write_spin(100));
+        assert!(lock.try_read_spin(0));
+        lock.read_unlock();
+    });
+}
+
+#[test]
+fn spin_counts_attempts() {
+    let mut attempts = 0;
+    assert!(!super::spin_exactly(5, || {
+        attempts += 1;
+        false
+    }));
+    assert_eq!(attempts, 6);
+
+    let mut attempts = 0;
+    assert!(!super::spin(5, || {
+        attempts += 1;
+        false
+    }));
+    assert_eq!(attempts, if crate::sys::ncpus() == 1 { 1 } else { 6 });
+}
+
+#[test]
+fn debug_is_opaque() {
+    let lock = RWLock::new();
+    assert_eq!(format!("{:?}", lock), "RWLock { <srwlock, opaque> }");
+    with_write(&lock, || assert_eq!(format!("{:?}", lock), "RWLock { <srwlock, opaque> }"));
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "RWLock storage was corrupted")]
+fn corrupted_guard_is_detected() {
+    let mut lock = RWLock::new();
+    lock.guard_after = 0;
+    unsafe { lock.write() };
+}
+
+#[test]
+fn upgrade_succeeds_for_sole_reader() {
+    let lock = RWLock::new();
+    unsafe {
+        lock.read();
+        assert!(lock.try_upgrade_shared_to_exclusive());
+        assert!(!lock.try_read());
+        lock.write_unlock();
+    }
+}
+
+#[test]
+fn upgrade_keeps_shared_lock_on_failure() {
+    let lock = RWLock::new();
+    with_read(&lock, || unsafe {
+        lock.read();
+        assert!(!lock.try_upgrade_shared_to_exclusive());
+        // Still held shared by both "readers".
+        assert!(!lock.try_write());
+        lock.read_unlock();
+    });
+}
+
+#[test]
+fn spin_then_block_on_free_lock() {
+    let lock = RWLock::new();
+    unsafe {
+        super::srw_write_spin_then_block(lock.inner.as_ptr(), 0);
+        assert!(!lock.try_read());
+        lock.write_unlock();
+    }
+}
+
+#[test]
+fn spin_then_block_waits_for_holder() {
+    use crate::sync::atomic::{AtomicBool, Ordering};
+    use crate::thread;
+    use crate::time::Duration;
+
+    static LOCK: RWLock = RWLock::new();
+    static ACQUIRED: AtomicBool = AtomicBool::new(false);
+
+    unsafe { LOCK.write() };
+    let waiter = thread::spawn(|| unsafe {
+        super::srw_write_spin_then_block(LOCK.inner.as_ptr(), 10);
+        ACQUIRED.store(true, Ordering::SeqCst);
+        LOCK.write_unlock();
+    });
+    thread::sleep(Duration::from_millis(50));
+    assert!(!ACQUIRED.load(Ordering::SeqCst));
+    unsafe { LOCK.write_unlock() };
+    waiter.join().unwrap();
+    assert!(ACQUIRED.load(Ordering::SeqCst));
+}
+
+#[cfg(feature = "lock_stats")]
+#[test]
+fn stats_balance() {
+    use super::LockStats;
+
+    let lock = RWLock::new();
+    with_read(&lock, || ());
+    with_write(&lock, || ());
+    unsafe {
+        assert!(lock.try_read());
+        lock.read_unlock();
+    }
+    let stats = lock.stats();
+    assert_eq!(
+        stats,
+        LockStats {
+            reads_acquired: 2,
+            reads_released: 2,
+            writes_acquired: 1,
+            writes_released: 1,
+            writer_blocked_by_readers: 0,
+            reader_blocked_by_writer: 0,
+        }
+    );
+}
+
+#[cfg(feature = "lock_stats")]
+#[test]
+fn stats_reveal_leaked_guard() {
+    let lock = RWLock::new();
+    unsafe { lock.write() };
+    let stats = lock.stats();
+    assert_ne!(stats.writes_acquired, stats.writes_released);
+    assert_eq!(stats.writes_acquired, 1);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn assert_unlocked_passes_when_free() {
+    let lock = RWLock::new();
+    unsafe { lock.debug_assert_unlocked() };
+    with_write(&lock, || ());
+    unsafe { lock.debug_assert_unlocked() };
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "RWLock expected to be unlocked")]
+fn assert_unlocked_panics_when_held() {
+    let lock = RWLock::new();
+    unsafe {
+        lock.read();
+        lock.debug_assert_unlocked();
+    }
+}
+
+#[test]
+fn packed_rwlock_under_contention() {
+    use super::PackedRwLock;
+    use crate::sync::Arc;
+    use crate::thread;
+
+    let lock = Arc::new(PackedRwLock::new((0u32, 0u32)));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    {
+                        let mut data = lock.write();
+                        data.0 += 1;
+                        data.1 += 1;
+                    }
+                    let data = lock.read();
+                    assert_eq!(data.0, data.1);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(*lock.read(), (4000, 4000));
+}
+
+// That a `ReadToken` can't be forged outside `rwlock.rs` isn't tested. A
+// doctest or UI test is compiled against std from the outside, where the
+// `pub(crate)` type can't be named at all, so a compile-fail test would pass
+// without ever reaching the private field. The field's privacy is what
+// enforces it.
+#[test]
+fn read_token_is_zero_sized() {
+    use super::ReadToken;
+    use crate::mem;
+
+    assert_eq!(mem::size_of::<ReadToken<'_>>(), 0);
+    let lock = RWLock::new();
+    with_read(&lock, || {
+        let _token = unsafe { lock.read_token() };
+    });
+}
+
+#[test]
+fn init_in_place_matches_new() {
+    use crate::mem::MaybeUninit;
+
+    let mut storage = MaybeUninit::<RWLock>::uninit();
+    let lock = unsafe {
+        RWLock::init_in_place(storage.as_mut_ptr());
+        &*storage.as_ptr()
+    };
+    with_write(lock, || unsafe { assert!(!lock.try_read()) });
+    with_read(lock, || unsafe {
+        assert!(lock.try_read());
+        lock.read_unlock();
+    });
+}
+
+#[test]
+fn probe_reports_held_lock() {
+    let lock = RWLock::new();
+    unsafe {
+        assert!(!lock.probe_locked());
+        with_write(&lock, || assert!(lock.probe_locked()));
+        with_read(&lock, || assert!(lock.probe_locked()));
+        assert!(!lock.probe_locked());
+    }
+}
+
+#[test]
+fn recursive_exclusive_same_thread() {
+    let lock = RecursiveRWLock::new();
+    unsafe {
+        lock.lock_exclusive_recursive();
+        lock.lock_exclusive_recursive();
+        lock.lock_exclusive_recursive();
+        lock.unlock_exclusive_recursive();
+        lock.unlock_exclusive_recursive();
+        assert!(!lock.inner().try_read());
+        lock.unlock_exclusive_recursive();
+        assert!(lock.inner().try_write());
+        lock.inner().write_unlock();
+    }
+}
+
+#[test]
+fn recursive_exclusive_blocks_other_threads() {
+    use crate::thread;
+
+    static LOCK: RecursiveRWLock = RecursiveRWLock::new();
+
+    unsafe {
+        LOCK.lock_exclusive_recursive();
+        LOCK.lock_exclusive_recursive();
+        assert!(!thread::spawn(|| LOCK.inner().try_write()).join().unwrap());
+        LOCK.unlock_exclusive_recursive();
+        assert!(!thread::spawn(|| LOCK.inner().try_write()).join().unwrap());
+        LOCK.unlock_exclusive_recursive();
+        let acquired = thread::spawn(|| {
+            LOCK.lock_exclusive_recursive();
+            LOCK.unlock_exclusive_recursive();
+            true
+        });
+        assert!(acquired.join().unwrap());
+    }
+}
+
+#[test]
+fn lock_both_normalizes_order() {
+    use super::lock_both;
+    use crate::thread;
+
+    static X: RWLock = RWLock::new();
+    static Y: RWLock = RWLock::new();
+
+    let xy = thread::spawn(|| {
+        for _ in 0..10_000 {
+            let _g = unsafe { lock_both(&X, &Y) };
+        }
+    });
+    let yx = thread::spawn(|| {
+        for _ in 0..10_000 {
+            let _g = unsafe { lock_both(&Y, &X) };
+        }
+    });
+    xy.join().unwrap();
+    yx.join().unwrap();
+    unsafe {
+        assert!(X.try_write());
+        assert!(Y.try_write());
+        X.write_unlock();
+        Y.write_unlock();
+    }
+}
+
+#[test]
+fn poison_survives_lock_cycles() {
+    let lock = PoisonRWLock::new();
+    assert!(!lock.is_poisoned());
+    with_write(lock.inner(), || lock.poison());
+    for _ in 0..3 {
+        with_read(lock.inner(), || assert!(lock.is_poisoned()));
+        with_write(lock.inner(), || assert!(lock.is_poisoned()));
+    }
+    lock.clear_poison();
+    assert!(!lock.is_poisoned());
+    with_write(lock.inner(), || assert!(!lock.is_poisoned()));
+}
+
+#[test]
+fn read_unlock_n_releases_every_hold() {
+    let lock = RWLock::new();
+    unsafe {
+        for _ in 0..3 {
+            lock.read();
+        }
+        lock.read_unlock_n(2);
+        assert!(!lock.try_write());
+        lock.read_unlock_n(1);
+        assert!(lock.try_write());
+        lock.write_unlock();
+        lock.read_unlock_n(0);
+        assert!(lock.try_write());
+        lock.write_unlock();
+    }
+}
+
+#[test]
+fn seq_rwlock_readers_never_see_torn_values() {
+    use super::SeqRwLock;
+    use crate::sync::atomic::{AtomicBool, Ordering};
+    use crate::thread;
+
+    // Two `u64`s, so no padding.
+    #[derive(Clone, Copy)]
+    struct Pair {
+        a: u64,
+        b: u64,
+    }
+
+    static LOCK: SeqRwLock<Pair> = SeqRwLock::new(Pair { a: 0, b: 0 });
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(|| {
+                while !DONE.load(Ordering::Relaxed) {
+                    let pair = unsafe { LOCK.read() };
+                    assert_eq!(pair.a, pair.b);
+                }
+            })
+        })
+        .collect();
+    for i in 1..=100_000 {
+        unsafe { LOCK.write(Pair { a: i, b: i }) };
+    }
+    DONE.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    let last = unsafe { LOCK.read() };
+    assert_eq!((last.a, last.b), (100_000, 100_000));
+}
+
+#[cfg(feature = "lock_latency")]
+#[test]
+fn latency_lands_in_a_higher_bucket_when_delayed() {
+    use crate::sync::atomic::{AtomicBool, Ordering};
+    use crate::thread;
+    use crate::time::Duration;
+
+    static LOCK: RWLock = RWLock::new();
+    static STARTED: AtomicBool = AtomicBool::new(false);
+
+    with_read(&LOCK, || ());
+    assert_eq!(LOCK.latency_buckets().iter().sum::<u64>(), 0);
+
+    unsafe { LOCK.write() };
+    let waiter = thread::spawn(|| {
+        STARTED.store(true, Ordering::SeqCst);
+        with_read(&LOCK, || ())
+    });
+    // Only start holding the lock once the waiter is about to block on it, so
+    // that a slow thread start can't eat into the 50ms.
+    while !STARTED.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+    thread::sleep(Duration::from_millis(50));
+    unsafe { LOCK.write_unlock() };
+    waiter.join().unwrap();
+
+    // The reader can only have blocked for close to the 50ms the writer
+    // held the lock, so it must land in the 10ms-100ms bucket or above.
+    let buckets = LOCK.latency_buckets();
+    assert_eq!(buckets.iter().sum::<u64>(), 1);
+    assert_eq!(buckets[5..].iter().sum::<u64>(), 1);
+}
+
+#[test]
+fn write_or_poisoned_still_grants_the_lock() {
+    let lock = PoisonRWLock::new();
+    unsafe {
+        assert_eq!(lock.write_or_poisoned(), Ok(()));
+        lock.poison();
+        lock.inner().write_unlock();
+
+        assert_eq!(lock.write_or_poisoned(), Err(()));
+        assert!(!lock.inner().try_read());
+        lock.clear_poison();
+        lock.inner().write_unlock();
+
+        assert_eq!(lock.write_or_poisoned(), Ok(()));
+        lock.inner().write_unlock();
+    }
+}
+
+#[test]
+fn try_write_or_read_falls_back_to_reading() {
+    use super::Acquired;
+    use crate::sync::atomic::{AtomicBool, Ordering};
+    use crate::thread;
+    use crate::time::Duration;
+
+    static LOCK: RWLock = RWLock::new();
+
+    unsafe {
+        assert_eq!(LOCK.try_write_or_read(), Acquired::Write);
+        assert!(!LOCK.try_read());
+        LOCK.write_unlock();
+    }
+
+    // With a reader already in, the exclusive attempt fails and this joins it.
+    with_read(&LOCK, || unsafe {
+        assert_eq!(LOCK.try_write_or_read(), Acquired::Read);
+        LOCK.read_unlock();
+    });
+
+    // With a writer in, neither kind of access is granted until it is done.
+    // Which one the waiter ends up with depends on whether it gets to try
+    // before the writer leaves.
+    static WRITER_DONE: AtomicBool = AtomicBool::new(false);
+    unsafe { LOCK.write() };
+    let waiter = thread::spawn(|| unsafe {
+        let acquired = LOCK.try_write_or_read();
+        assert!(WRITER_DONE.load(Ordering::SeqCst));
+        match acquired {
+            Acquired::Write => LOCK.write_unlock(),
+            Acquired::Read => LOCK.read_unlock(),
+        }
+    });
+    thread::sleep(Duration::from_millis(20));
+    WRITER_DONE.store(true, Ordering::SeqCst);
+    unsafe { LOCK.write_unlock() };
+    waiter.join().unwrap();
+}
+
+#[test]
+fn nested_recursive_reads_touch_the_lock_once() {
+    use crate::sync::atomic::{AtomicBool, Ordering};
+    use crate::thread;
+    use crate::time::Duration;
+
+    static LOCK: RWLock = RWLock::new();
+    static WRITTEN: AtomicBool = AtomicBool::new(false);
+
+    unsafe {
+        LOCK.read_recursive();
+        // Queue a writer behind the outer read. A nested plain `read` could
+        // now block behind it; a nested `read_recursive` must not.
+        let writer = thread::spawn(|| {
+            with_write(&LOCK, || WRITTEN.store(true, Ordering::SeqCst));
+        });
+        thread::sleep(Duration::from_millis(20));
+        LOCK.read_recursive();
+        LOCK.read_recursive();
+        LOCK.read_unlock_recursive();
+        LOCK.read_unlock_recursive();
+        assert!(!WRITTEN.load(Ordering::SeqCst));
+        LOCK.read_unlock_recursive();
+        writer.join().unwrap();
+    }
+    assert!(WRITTEN.load(Ordering::SeqCst));
+    unsafe {
+        assert!(LOCK.try_write());
+        LOCK.write_unlock();
+    }
+}
+
+#[test]
+fn recursive_read_depth_overflow_is_detected() {
+    use super::READ_DEPTHS;
+    use crate::thread;
+
+    // On its own thread, so the panic can't leave this thread's depth table
+    // (or the lock) in a state other tests could see.
+    let result = thread::spawn(|| {
+        static LOCK: RWLock = RWLock::new();
+        unsafe { LOCK.read_recursive() };
+        READ_DEPTHS.with(|depths| depths.borrow_mut()[0].1 = usize::MAX);
+        unsafe { LOCK.read_recursive() };
+    })
+    .join();
+    let err = result.unwrap_err();
+    let msg = err.downcast_ref::<String>().map(|s| &s[..]).or(err.downcast_ref::<&str>().copied());
+    assert_eq!(msg, Some("reader count overflow"));
+}
+
+#[test]
+fn distributed_rwlock_excludes_writers() {
+    use super::DistributedRwLock;
+    use crate::cell::UnsafeCell;
+    use crate::thread;
+
+    struct Shared {
+        lock: DistributedRwLock,
+        pair: UnsafeCell<(u64, u64)>,
+    }
+    unsafe impl Sync for Shared {}
+
+    static SHARED: Shared =
+        Shared { lock: DistributedRwLock::new(), pair: UnsafeCell::new((0, 0)) };
+
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || unsafe {
+                for _ in 0..10_000 {
+                    if i % 4 == 0 {
+                        SHARED.lock.write();
+                        let pair = &mut *SHARED.pair.get();
+                        pair.0 += 1;
+                        pair.1 += 1;
+                        SHARED.lock.write_unlock();
+                    } else {
+                        SHARED.lock.read();
+                        let pair = *SHARED.pair.get();
+                        assert_eq!(pair.0, pair.1);
+                        SHARED.lock.read_unlock();
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(unsafe { *SHARED.pair.get() }, (20_000, 20_000));
+}
+
+// Compare the cost of a read acquisition while other threads hammer the same
+// lock for reading, one background reader for every other CPU, so that the
+// results show how each lock scales on the machine running them.
+fn bench_contended_reads(b: &mut test::Bencher, read: fn()) {
+    use crate::sync::atomic::{AtomicBool, Ordering};
+    use crate::sync::Arc;
+    use crate::thread;
+
+    let readers = crate::sys::ncpus().saturating_sub(1);
+    let done = Arc::new(AtomicBool::new(false));
+    let background: Vec<_> = (0..readers)
+        .map(|_| {
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    read();
+                }
+            })
+        })
+        .collect();
+    b.iter(read);
+    done.store(true, Ordering::Relaxed);
+    for thread in background {
+        thread.join().unwrap();
+    }
+}
+
+#[bench]
+fn bench_rwlock_contended_reads(b: &mut test::Bencher) {
+    static LOCK: RWLock = RWLock::new();
+    bench_contended_reads(b, || unsafe {
+        LOCK.read();
+        LOCK.read_unlock();
+    });
+}
+
+#[bench]
+fn bench_distributed_rwlock_contended_reads(b: &mut test::Bencher) {
+    use super::DistributedRwLock;
+
+    static LOCK: DistributedRwLock = DistributedRwLock::new();
+    bench_contended_reads(b, || unsafe {
+        LOCK.read();
+        LOCK.read_unlock();
+    });
+}
+
+#[test]
+fn try_status_reports_each_outcome() {
+    use super::TryStatus;
+
+    let lock = PoisonRWLock::new();
+    unsafe {
+        assert_eq!(lock.try_read_status(), TryStatus::Acquired);
+        assert_eq!(lock.try_write_status(), TryStatus::WouldBlock);
+        lock.inner().read_unlock();
+
+        assert_eq!(lock.try_write_status(), TryStatus::Acquired);
+        assert_eq!(lock.try_read_status(), TryStatus::WouldBlock);
+        lock.poison();
+        lock.inner().write_unlock();
+
+        assert_eq!(lock.try_read_status(), TryStatus::AcquiredPoisoned);
+        lock.inner().read_unlock();
+        assert_eq!(lock.try_write_status(), TryStatus::AcquiredPoisoned);
+        lock.inner().write_unlock();
+    }
+}
+
+#[test]
+fn write_ticket_round_trips_through_an_integer() {
+    use super::WriteTicket;
+
+    // Stands in for native code that holds on to the ticket and passes it
+    // back when done.
+    extern "C" fn callback(ticket: usize) {
+        unsafe { RWLock::release_write_raw(WriteTicket::from_raw(ticket)) }
+    }
+
+    let lock = RWLock::new();
+    unsafe {
+        let ticket = lock.acquire_write_raw();
+        assert!(!lock.try_read());
+        callback(ticket.into_raw());
+        assert!(lock.try_write());
+        lock.write_unlock();
+    }
+}
+
+#[test]
+fn write_yield_lets_another_writer_in() {
+    use crate::sync::atomic::{AtomicUsize, Ordering};
+    use crate::thread;
+
+    static LOCK: RWLock = RWLock::new();
+    // Whose turn it is, 0 or 1. Only changed while holding the lock.
+    static TURN: AtomicUsize = AtomicUsize::new(0);
+
+    let writers: Vec<_> = (0..2)
+        .map(|me| {
+            thread::spawn(move || unsafe {
+                LOCK.write();
+                for _ in 0..100 {
+                    // Only the other writer can hand back the turn, so this
+                    // can only get past here if `write_yield` lets it run.
+                    while TURN.load(Ordering::Relaxed) != me {
+                        LOCK.write_yield();
+                    }
+                    TURN.store(1 - me, Ordering::Relaxed);
+                }
+                LOCK.write_unlock();
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+}
+
+#[cfg(feature = "lock_stats")]
+#[test]
+fn stats_count_writers_blocked_by_readers() {
+    use crate::thread;
+
+    static LOCK: RWLock = RWLock::new();
+
+    unsafe { LOCK.read() };
+    let writer = thread::spawn(|| with_write(&LOCK, || ()));
+    // The writer is counted just before it blocks, so once it shows up it is
+    // safe to let it in.
+    while LOCK.stats().writer_blocked_by_readers != 1 {
+        thread::yield_now();
+    }
+    unsafe { LOCK.read_unlock() };
+    writer.join().unwrap();
+
+    let stats = LOCK.stats();
+    assert_eq!(stats.writer_blocked_by_readers, 1);
+    assert_eq!(stats.reader_blocked_by_writer, 0);
+}
+
+#[test]
+fn try_write_until_cancel_outcomes() {
+    use super::AcquireOutcome;
+    use crate::sync::atomic::{AtomicBool, Ordering};
+    use crate::thread;
+    use crate::time::{Duration, Instant};
+
+    static LOCK: RWLock = RWLock::new();
+    static CANCEL: AtomicBool = AtomicBool::new(false);
+
+    let far = Instant::now() + Duration::from_secs(60);
+    unsafe {
+        assert_eq!(LOCK.try_write_until_cancel(far, &CANCEL), AcquireOutcome::Acquired);
+
+        let waiter = thread::spawn(move || {
+            let start = Instant::now();
+            let outcome = LOCK.try_write_until_cancel(far, &CANCEL);
+            (outcome, start.elapsed())
+        });
+        thread::sleep(Duration::from_millis(20));
+        CANCEL.store(true, Ordering::Relaxed);
+        let (outcome, waited) = waiter.join().unwrap();
+        assert_eq!(outcome, AcquireOutcome::Canceled);
+        assert!(waited < Duration::from_secs(10));
+        CANCEL.store(false, Ordering::Relaxed);
+
+        let soon = Instant::now() + Duration::from_millis(10);
+        let waiter = thread::spawn(move || LOCK.try_write_until_cancel(soon, &CANCEL));
+        assert_eq!(waiter.join().unwrap(), AcquireOutcome::TimedOut);
+
+        LOCK.write_unlock();
+    }
+}
+
+#[test]
+fn backoff_escalates_from_pausing_to_sleeping() {
+    use super::Backoff;
+    use super::BackoffPhase::*;
+    use crate::time::Duration;
+
+    let mut backoff = Backoff { step: 0 };
+    let mut phases = Vec::new();
+    for _ in 0..12 {
+        phases.push(backoff.phase());
+        // A zero limit keeps the sleep phase from actually sleeping.
+        backoff.snooze_within(Duration::from_secs(0));
+    }
+    assert_eq!(
+        phases,
+        [
+            Pause(1),
+            Pause(2),
+            Pause(4),
+            Pause(8),
+            Pause(16),
+            Pause(32),
+            Yield,
+            Yield,
+            Yield,
+            Yield,
+            Sleep,
+            Sleep
+        ]
+    );
+}
+
+#[test]
+fn backoff_spin_never_sleeps() {
+    use super::BackoffPhase::*;
+    use super::{Backoff, YIELD_STEPS};
+
+    let mut backoff = Backoff { step: 0 };
+    for _ in 0..100 {
+        backoff.spin();
+        assert_ne!(backoff.phase(), Sleep);
+    }
+    assert_eq!(backoff.phase(), Yield);
+
+    // Even a backoff that `snooze` already took to the sleep phase drops
+    // back to yielding.
+    let mut backoff = Backoff { step: YIELD_STEPS };
+    backoff.spin();
+    assert_eq!(backoff.phase(), Yield);
+}
+
+#[test]
+fn try_write_spin_ns_gives_up_after_its_budget() {
+    use crate::time::{Duration, Instant};
+
+    let lock = RWLock::new();
+    with_read(&lock, || unsafe {
+        let start = Instant::now();
+        assert!(!lock.try_write_spin_ns(2_000_000));
+        let spun = start.elapsed();
+        if crate::sys::ncpus() > 1 {
+            assert!(spun >= Duration::from_millis(1), "gave up after {:?}", spun);
+        }
+        assert!(spun < Duration::from_secs(1), "spun for {:?}", spun);
+    });
+    unsafe {
+        assert!(lock.try_write_spin_ns(0));
+        lock.write_unlock();
+    }
+}
+
+#[cfg(feature = "lock_stats")]
+#[test]
+fn ever_contended_flags_only_contended_locks() {
+    use crate::thread;
+
+    static QUIET: RWLock = RWLock::new();
+    static BUSY: RWLock = RWLock::new();
+
+    with_write(&QUIET, || ());
+    with_read(&QUIET, || ());
+    assert!(!QUIET.ever_contended());
+
+    unsafe { BUSY.write() };
+    let reader = thread::spawn(|| with_read(&BUSY, || ()));
+    // The reader is counted just before it blocks.
+    while !BUSY.ever_contended() {
+        thread::yield_now();
+    }
+    unsafe { BUSY.write_unlock() };
+    reader.join().unwrap();
+}
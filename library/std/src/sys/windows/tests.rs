@@ -0,0 +1,75 @@
+use super::{dur2timeout, ncpus};
+use crate::sys::c;
+use crate::time::Duration;
+
+#[test]
+fn dur2timeout_boundaries() {
+    assert_eq!(dur2timeout(Duration::from_secs(0)), 0);
+    assert_eq!(dur2timeout(Duration::from_nanos(1)), 1);
+    assert_eq!(dur2timeout(Duration::from_nanos(999_999)), 1);
+    assert_eq!(dur2timeout(Duration::from_nanos(1_000_000)), 1);
+    assert_eq!(dur2timeout(Duration::from_nanos(1_000_001)), 2);
+    assert_eq!(dur2timeout(Duration::from_millis(c::INFINITE as u64 + 1)), c::INFINITE);
+    assert_eq!(dur2timeout(Duration::from_secs(u64::MAX)), c::INFINITE);
+}
+
+#[test]
+fn ncpus_is_plausible_and_stable() {
+    let n = ncpus();
+    assert!(n >= 1);
+    assert_eq!(ncpus(), n);
+}
+
+#[test]
+fn flush_process_write_buffers_resolves() {
+    assert!(crate::sys::compat::lookup("kernel32", "FlushProcessWriteBuffers").is_some());
+    unsafe { super::flush_process_write_buffers() };
+}
+
+#[test]
+fn flush_process_write_buffers_fallback_runs() {
+    use crate::sync::atomic::{fence, AtomicBool, Ordering};
+    use crate::sys::compat::force_fallback;
+
+    static FALLBACK_RAN: AtomicBool = AtomicBool::new(false);
+
+    // A binding local to this test, since `c::FlushProcessWriteBuffers` may
+    // already be resolved by the time the fallback is forced.
+    compat_fn! {
+        kernel32:
+
+        pub fn FlushProcessWriteBuffers() -> () {
+            fence(Ordering::SeqCst);
+            FALLBACK_RAN.store(true, Ordering::SeqCst)
+        }
+    }
+
+    let _forced = force_fallback("FlushProcessWriteBuffers");
+    unsafe { FlushProcessWriteBuffers() };
+    assert!(FALLBACK_RAN.load(Ordering::SeqCst));
+}
+
+#[test]
+fn os_version_is_plausible_and_stable() {
+    let (major, minor, build) = super::os_version();
+    // Windows XP is 5.1.
+    assert!((major, minor) >= (5, 1));
+    assert!(build > 0);
+    assert_eq!(super::os_version(), (major, minor, build));
+}
+
+#[test]
+fn current_cpu_indexes_a_slot() {
+    use super::current_cpu;
+
+    // Not a power of two, so that a reduction done with a mask would show.
+    const SLOTS: usize = 7;
+    assert!(crate::sys::compat::lookup("kernel32", "GetCurrentProcessorNumber").is_some());
+    let slots: Vec<_> = (0..100).map(|_| current_cpu(SLOTS)).collect();
+    assert!(slots.iter().all(|&slot| slot < SLOTS));
+    // Rapid calls usually stay on one processor, but the thread may migrate
+    // at any point, so only check that most of them agree.
+    let same = slots.iter().filter(|&&slot| slot == slots[0]).count();
+    assert!(same > 50);
+    assert_eq!(current_cpu(1), 0);
+}
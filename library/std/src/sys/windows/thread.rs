@@ -1,3 +1,4 @@
+use crate::cmp;
 use crate::ffi::CStr;
 use crate::io;
 use crate::ptr;
@@ -10,6 +11,9 @@ use libc::c_void;
 
 use super::to_u16s;
 
+#[cfg(test)]
+mod tests;
+
 pub const DEFAULT_MIN_STACK_SIZE: usize = 2 * 1024 * 1024;
 
 pub struct Thread {
@@ -98,6 +102,56 @@ impl Thread {
     }
 }
 
+/// Cancels the synchronous I/O operation `thread` is blocked in, returning
+/// whether there was one to cancel.
+///
+/// This is for unblocking threads during a controlled shutdown. The handle
+/// must be a valid thread handle with `THREAD_TERMINATE` access.
+#[allow(dead_code)]
+pub unsafe fn cancel_synchronous_io(thread: c::HANDLE) -> io::Result<bool> {
+    if c::CancelSynchronousIo(thread) != 0 {
+        Ok(true)
+    } else if c::GetLastError() == c::ERROR_NOT_FOUND {
+        Ok(false)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Sleeps for `dur` on a high-resolution waitable timer, which can wake well
+/// within the default ~15ms timer tick that `Sleep` is limited to.
+///
+/// Such timers need Windows 10 1803; elsewhere this falls back to `Sleep`.
+/// Each call creates and closes its own timer, so it is meant for short
+/// waits in timed retry loops rather than as a general `Thread::sleep`.
+pub(crate) fn high_res_sleep(dur: Duration) {
+    // A due time of 0 would be read as an absolute time, long past, rather
+    // than as no wait at all.
+    if dur.as_nanos() == 0 {
+        return;
+    }
+    unsafe {
+        let timer = c::CreateWaitableTimerExW(
+            ptr::null_mut(),
+            ptr::null(),
+            c::CREATE_WAITABLE_TIMER_HIGH_RESOLUTION,
+            c::TIMER_ALL_ACCESS,
+        );
+        if timer.is_null() {
+            return c::Sleep(super::dur2timeout(dur));
+        }
+        let timer = Handle::new(timer);
+        // A negative due time is relative to now, in 100ns units. Round up so
+        // that the sleep is never shorter than asked for.
+        let ticks = cmp::min((dur.as_nanos() + 99) / 100, i64::MAX as u128) as i64;
+        let due: c::LARGE_INTEGER = -ticks;
+        if c::SetWaitableTimer(timer.raw(), &due, 0, None, ptr::null_mut(), c::FALSE) == 0 {
+            return c::Sleep(super::dur2timeout(dur));
+        }
+        c::WaitForSingleObject(timer.raw(), c::INFINITE);
+    }
+}
+
 #[cfg_attr(test, allow(dead_code))]
 pub mod guard {
     pub type Guard = !;
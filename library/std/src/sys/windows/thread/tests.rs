@@ -0,0 +1,83 @@
+use crate::ptr;
+use crate::slice;
+use crate::sys::c;
+use crate::thread;
+
+// Thread descriptions need Windows 10 1607; on older systems the compat
+// fallbacks report E_NOTIMPL and there is nothing to check.
+#[test]
+fn name_round_trips_through_description() {
+    let name = thread::Builder::new()
+        .name("rust-description-test".to_string())
+        .spawn(|| unsafe {
+            let mut desc: c::LPWSTR = ptr::null_mut();
+            let hr = c::GetThreadDescription(c::GetCurrentThread(), &mut desc);
+            if hr == c::E_NOTIMPL {
+                return None;
+            }
+            assert!(hr >= 0, "GetThreadDescription failed: {:#x}", hr);
+            let len = (0..).take_while(|&i| *desc.offset(i) != 0).count();
+            let name = String::from_utf16(slice::from_raw_parts(desc, len)).unwrap();
+            c::LocalFree(desc as c::HLOCAL);
+            Some(name)
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    if let Some(name) = name {
+        assert_eq!(name, "rust-description-test");
+    }
+}
+
+#[test]
+fn cancel_synchronous_io_without_pending_io() {
+    use super::cancel_synchronous_io;
+    use crate::os::windows::io::AsRawHandle;
+    use crate::sync::mpsc;
+    use crate::sys::compat;
+
+    assert!(compat::lookup("kernel32", "CancelSynchronousIo").is_some());
+
+    // A thread blocked on a channel isn't in a synchronous I/O call, so
+    // there is nothing to cancel.
+    let (tx, rx) = mpsc::channel::<()>();
+    let waiter = thread::spawn(move || rx.recv().unwrap());
+    let canceled = unsafe { cancel_synchronous_io(waiter.as_raw_handle() as c::HANDLE) };
+    tx.send(()).unwrap();
+    waiter.join().unwrap();
+    assert_eq!(canceled.unwrap(), false);
+}
+
+#[test]
+fn high_res_sleep_is_close() {
+    use super::high_res_sleep;
+    use crate::time::{Duration, Instant};
+
+    let requested = Duration::from_millis(2);
+    let shortest = (0..5)
+        .map(|_| {
+            let start = Instant::now();
+            high_res_sleep(requested);
+            start.elapsed()
+        })
+        .min()
+        .unwrap();
+    assert!(shortest >= requested);
+    // High-resolution timers arrived in Windows 10 1803 (build 17134). Before
+    // that this is `Sleep`, which rounds up to the timer tick.
+    if crate::sys::os_version() >= (10, 0, 17134) {
+        assert!(shortest < Duration::from_millis(10), "slept for {:?}", shortest);
+    }
+}
+
+#[test]
+fn get_thread_id_matches_the_thread() {
+    use crate::os::windows::io::AsRawHandle;
+    use crate::sys::compat::lookup;
+
+    assert!(lookup("kernel32", "GetThreadId").is_some());
+    let child = thread::spawn(|| unsafe { c::GetCurrentThreadId() });
+    let id = unsafe { c::GetThreadId(child.as_raw_handle() as c::HANDLE) };
+    assert_ne!(id, 0);
+    assert_eq!(child.join().unwrap(), id);
+}
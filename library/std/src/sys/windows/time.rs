@@ -163,7 +163,7 @@ fn intervals2dur(intervals: u64) -> Duration {
     Duration::new(intervals / INTERVALS_PER_SEC, ((intervals % INTERVALS_PER_SEC) * 100) as u32)
 }
 
-mod perf_counter {
+pub(crate) mod perf_counter {
     use super::NANOS_PER_SEC;
     use crate::sync::atomic::{AtomicUsize, Ordering::SeqCst};
     use crate::sys::c;
@@ -220,7 +220,15 @@ mod perf_counter {
         }
     }
 
-    fn query() -> c::LARGE_INTEGER {
+    /// The performance counter reading `nanos` from now, for spin loops that
+    /// compare against the raw counter on every iteration rather than
+    /// converting each reading into an `Instant`.
+    pub fn deadline_after_nanos(nanos: u64) -> c::LARGE_INTEGER {
+        let ticks = mul_div_u64(nanos, frequency() as u64, NANOS_PER_SEC);
+        query().saturating_add(ticks as c::LARGE_INTEGER)
+    }
+
+    pub fn query() -> c::LARGE_INTEGER {
         let mut qpc_value: c::LARGE_INTEGER = 0;
         cvt(unsafe { c::QueryPerformanceCounter(&mut qpc_value) }).unwrap();
         qpc_value